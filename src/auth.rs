@@ -0,0 +1,421 @@
+use crate::SETTINGS;
+use awc::ClientRequest;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+// the distribution token spec says to assume this when the token service doesn't send expires_in.
+const DEFAULT_TOKEN_LIFETIME_SECONDS: u64 = 60;
+// tokens are dropped a little before they actually expire, so one doesn't run out halfway through a lookup.
+const TOKEN_EXPIRY_MARGIN_SECONDS: u64 = 10;
+// however long a token service says a token is good for, it is asked again after a day.
+const MAX_TOKEN_LIFETIME_SECONDS: u64 = 86400;
+
+lazy_static! {
+    pub static ref TOKEN_CACHE: TokenCache = TokenCache::new(AuthSettings::from_settings());
+}
+
+/// AuthSettings is the `[auth]` table of tolerable.toml.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct AuthSettings {
+    /// discovery_ttl_seconds is how long what a registry's /v2/ endpoint said about auth is trusted
+    pub discovery_ttl_seconds: u64,
+    /// credential_helper_ttl_seconds is how long what a docker credential helper said is trusted
+    pub credential_helper_ttl_seconds: u64,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        AuthSettings {
+            discovery_ttl_seconds: 3600,
+            credential_helper_ttl_seconds: 300,
+        }
+    }
+}
+
+impl AuthSettings {
+    pub fn from_settings() -> AuthSettings {
+        SETTINGS.read().unwrap().get::<AuthSettings>("auth").unwrap_or_default()
+    }
+}
+
+/// BearerChallenge is the token service a registry sent us to in its `www-authenticate` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BearerChallenge {
+    /// realm is the url of the token service
+    pub realm: String,
+    /// service is the name the token service knows the registry by
+    pub service: String,
+}
+
+impl BearerChallenge {
+    /// scope_for is the token a pull of `repository` needs from this token service.
+    pub fn scope_for(&self, repository: &str) -> TokenScope {
+        TokenScope {
+            realm: self.realm.clone(),
+            service: self.service.clone(),
+            scope: format!("repository:{}:pull", repository),
+            credential: String::new(),
+        }
+    }
+}
+
+/// AuthChallenge is the kind of authentication a registry asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthChallenge {
+    /// Anonymous means the registry answered without asking for anything.
+    Anonymous,
+    /// Bearer means fetching a token from a token service and sending that.
+    Bearer(BearerChallenge),
+    /// Basic means sending the registry credentials with every request.
+    Basic,
+}
+
+/// RegistryAuth is what gets attached to requests to a registry, chosen by the challenge it sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryAuth {
+    Bearer(String),
+    Basic { user: String, secret: String },
+}
+
+impl RegistryAuth {
+    pub fn apply(&self, request: ClientRequest) -> ClientRequest {
+        match self {
+            RegistryAuth::Bearer(token) => request.bearer_auth(token),
+            RegistryAuth::Basic { user, secret } => request.basic_auth(user, secret),
+        }
+    }
+}
+
+/// Challenge is one challenge from a `www-authenticate` header (RFC 7235): an auth scheme and either its parameters
+/// or a single token68 value.  Schemes and parameter names are case-insensitive, so both are kept lowercased.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Challenge {
+    pub scheme: String,
+    pub params: HashMap<String, String>,
+    pub token68: Option<String>,
+}
+
+impl Challenge {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|p| p.as_str())
+    }
+}
+
+/// parse_challenges splits a `www-authenticate` header into its challenges.  Parameters may come in any order,
+/// quoted or not, and a header may carry several challenges separated by commas.  Anything unparseable is skipped.
+pub fn parse_challenges(header: &str) -> Vec<Challenge> {
+    let mut parser = ChallengeParser {
+        chars: header.chars().collect(),
+        pos: 0,
+    };
+    let mut challenges: Vec<Challenge> = Vec::new();
+    loop {
+        parser.skip_separators();
+        if parser.at_end() {
+            break;
+        }
+        let scheme = parser.token();
+        if scheme.is_empty() {
+            // not something a challenge can start with, step over it.
+            parser.pos += 1;
+            continue;
+        }
+        let mut challenge = Challenge {
+            scheme: scheme.to_lowercase(),
+            ..Challenge::default()
+        };
+        parser.skip_whitespace();
+        if let Some(token68) = parser.token68() {
+            challenge.token68 = Some(token68);
+            challenges.push(challenge);
+            continue;
+        }
+        loop {
+            parser.skip_separators();
+            let start = parser.pos;
+            let name = parser.token();
+            parser.skip_whitespace();
+            if name.is_empty() || parser.peek() != Some('=') {
+                // the next challenge's scheme, or the end of the header.
+                parser.pos = start;
+                break;
+            }
+            parser.pos += 1;
+            parser.skip_whitespace();
+            let value = if parser.peek() == Some('"') {
+                parser.quoted_string()
+            } else {
+                parser.token()
+            };
+            challenge.params.insert(name.to_lowercase(), value);
+        }
+        challenges.push(challenge);
+    }
+    challenges
+}
+
+/// bearer_challenge picks the bearer challenge out of a `www-authenticate` header, along with the scope it asked
+/// for, if any.
+pub fn bearer_challenge(header: &str) -> Option<(BearerChallenge, Option<String>)> {
+    let challenge = parse_challenges(header)
+        .into_iter()
+        .find(|c| c.scheme == "bearer" && c.param("realm").is_some())?;
+    Some((
+        BearerChallenge {
+            realm: challenge.param("realm")?.to_string(),
+            service: challenge.param("service").unwrap_or("").to_string(),
+        },
+        challenge.param("scope").map(|s| s.to_string()),
+    ))
+}
+
+/// auth_challenge picks how to authenticate from a `www-authenticate` header.  Bearer wins when a registry offers
+/// both, since the basic challenge is usually only there for browsers.
+pub fn auth_challenge(header: &str) -> Option<(AuthChallenge, Option<String>)> {
+    if let Some((bearer, scope)) = bearer_challenge(header) {
+        return Some((AuthChallenge::Bearer(bearer), scope));
+    }
+    parse_challenges(header)
+        .iter()
+        .find(|c| c.scheme == "basic")
+        .map(|_| (AuthChallenge::Basic, None))
+}
+
+struct ChallengeParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl ChallengeParser {
+    fn at_end(&self) -> bool {
+        self.pos >= self.chars.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_separators(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t') | Some(',')) {
+            self.pos += 1;
+        }
+    }
+
+    fn token(&mut self) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(is_tchar) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    /// token68 reads a token68 value (`Basic dXNlcjpwYXNz`), leaving the position alone when what follows is
+    /// parameters instead.
+    fn token68(&mut self) -> Option<String> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || "-._~+/".contains(c))
+        {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return None;
+        }
+        while self.peek() == Some('=') {
+            self.pos += 1;
+        }
+        let end = self.pos;
+        self.skip_whitespace();
+        // a token68 is the whole of its challenge; `realm="..."` and friends are followed by their value instead.
+        if self.at_end() || self.peek() == Some(',') {
+            return Some(self.chars[start..end].iter().collect());
+        }
+        self.pos = start;
+        None
+    }
+
+    fn quoted_string(&mut self) -> String {
+        let mut value = String::new();
+        // opening quote
+        self.pos += 1;
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                '"' => break,
+                '\\' => {
+                    if let Some(escaped) = self.peek() {
+                        value.push(escaped);
+                        self.pos += 1;
+                    }
+                }
+                _ => value.push(c),
+            }
+        }
+        value
+    }
+}
+
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+/// TokenScope identifies a bearer token: which token service issued it, for which registry, granting what, to whom.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TokenScope {
+    pub realm: String,
+    pub service: String,
+    pub scope: String,
+    /// credential is the fingerprint of the credential the token is asked for with, empty for anonymous tokens, so
+    /// a token one pod's pull secret got is never used for another pod
+    pub credential: String,
+}
+
+impl TokenScope {
+    /// url is where the token is requested from.
+    pub fn url(&self) -> String {
+        let mut url = self.realm.clone();
+        let mut separator = if url.contains('?') { '&' } else { '?' };
+        for (name, value) in [("service", &self.service), ("scope", &self.scope)] {
+            if !value.is_empty() {
+                url.push_str(&format!("{separator}{name}={}", query_escape(value)));
+                separator = '&';
+            }
+        }
+        url
+    }
+}
+
+/// query_escape percent-encodes a query parameter value.  `:` and `/` are allowed in a query and are left alone, so
+/// scopes read as they do in the registry docs.
+fn query_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~:/".contains(&b) {
+            escaped.push(b as char);
+        } else {
+            escaped.push_str(&format!("%{b:02X}"));
+        }
+    }
+    escaped
+}
+
+/// TokenResponse is the body a token service answers with.  Older token services send `token`, OAuth2 style ones
+/// send `access_token`, and some send both.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TokenResponse {
+    pub token: Option<String>,
+    pub access_token: Option<String>,
+    pub expires_in: Option<u64>,
+    pub issued_at: Option<String>,
+}
+
+impl TokenResponse {
+    pub fn bearer(&self) -> Option<&str> {
+        self.token.as_deref().or(self.access_token.as_deref())
+    }
+
+    /// lifetime is how much longer the token can be used, counted from `now`.
+    pub fn lifetime(&self, now: OffsetDateTime) -> Duration {
+        let expires_in = self.expires_in.unwrap_or(DEFAULT_TOKEN_LIFETIME_SECONDS).min(MAX_TOKEN_LIFETIME_SECONDS);
+        let issued_at = self
+            .issued_at
+            .as_deref()
+            .and_then(|i| OffsetDateTime::parse(i, &Rfc3339).ok())
+            // a token claiming to be from the future is treated as issued now.
+            .map(|i| i.min(now))
+            .unwrap_or(now);
+        let expires_at = match issued_at.checked_add(time::Duration::seconds(expires_in as i64)) {
+            Some(e) => e,
+            None => return Duration::ZERO,
+        };
+        let remaining = (expires_at - now).whole_seconds() - TOKEN_EXPIRY_MARGIN_SECONDS as i64;
+        Duration::from_secs(remaining.max(0) as u64)
+    }
+}
+
+struct CachedToken {
+    token: String,
+    expires: Instant,
+}
+
+/// TokenCache remembers how each registry wants to be authenticated and the tokens token services handed out, so
+/// a lookup can go straight to the manifest when a token for its scope is still good.  What a registry wants is
+/// rediscovered every discovery_ttl_seconds.
+pub struct TokenCache {
+    settings: AuthSettings,
+    challenges: Mutex<HashMap<String, (AuthChallenge, Instant)>>,
+    tokens: Mutex<HashMap<TokenScope, CachedToken>>,
+}
+
+impl TokenCache {
+    pub fn new(settings: AuthSettings) -> TokenCache {
+        TokenCache {
+            settings,
+            challenges: Mutex::new(HashMap::new()),
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// challenge returns what `registry` was last found to require, unless that was too long ago.
+    pub fn challenge(&self, registry: &str) -> Option<AuthChallenge> {
+        let now = Instant::now();
+        let mut challenges = self.challenges.lock().unwrap();
+        if let Some((challenge, expires)) = challenges.get(registry) {
+            if *expires > now {
+                return Some(challenge.clone());
+            }
+            challenges.remove(registry);
+        }
+        None
+    }
+
+    pub fn remember_challenge(&self, registry: &str, challenge: AuthChallenge) {
+        let expires = Instant::now() + Duration::from_secs(self.settings.discovery_ttl_seconds);
+        self.challenges
+            .lock()
+            .unwrap()
+            .insert(registry.to_string(), (challenge, expires));
+    }
+
+    /// get returns a token for `scope` if there is one that hasn't expired.
+    pub fn get(&self, scope: &TokenScope) -> Option<String> {
+        let now = Instant::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        if let Some(cached) = tokens.get(scope) {
+            if cached.expires > now {
+                return Some(cached.token.clone());
+            }
+            tokens.remove(scope);
+        }
+        None
+    }
+
+    /// insert remembers a token for `lifetime`.  Tokens that are already as good as expired aren't kept.
+    pub fn insert(&self, scope: TokenScope, token: String, lifetime: Duration) {
+        if lifetime.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, t| t.expires > now);
+        tokens.insert(
+            scope,
+            CachedToken {
+                token,
+                expires: now + lifetime,
+            },
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use crate::errors::LookupError;
+use crate::manifest::ImagePlatforms;
+use crate::SETTINGS;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    pub static ref PLATFORM_CACHE: PlatformCache = PlatformCache::new(CacheSettings::from_settings());
+}
+
+/// CacheSettings is the `[cache]` table of tolerable.toml.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CacheSettings {
+    /// max_entries is the most images we remember answers for
+    pub max_entries: usize,
+    /// ttl_seconds is how long a successful lookup is trusted
+    pub ttl_seconds: u64,
+    /// negative_ttl_seconds is how long a failed lookup is remembered before the registry is asked again
+    pub negative_ttl_seconds: u64,
+    /// stale_seconds is how long past its ttl a successful lookup may still be served while it is revalidated
+    pub stale_seconds: u64,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            max_entries: 1000,
+            ttl_seconds: 3600,
+            negative_ttl_seconds: 60,
+            stale_seconds: 86400,
+        }
+    }
+}
+
+impl CacheSettings {
+    pub fn from_settings() -> CacheSettings {
+        SETTINGS.read().unwrap().get::<CacheSettings>("cache").unwrap_or_default()
+    }
+}
+
+struct CacheEntry {
+    value: Result<ImagePlatforms, LookupError>,
+    expires: Instant,
+    stale_until: Instant,
+    last_used: Instant,
+    revalidating: bool,
+}
+
+/// PlatformCache remembers lookup results per image.  Successful lookups and failures expire on separate
+/// schedules, and errors that are likely to go away on their own (the registry was unreachable) aren't kept at all.
+/// Expired successful lookups stay around for a while longer so they can be served while they are revalidated.
+pub struct PlatformCache {
+    settings: CacheSettings,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl PlatformCache {
+    pub fn new(settings: CacheSettings) -> PlatformCache {
+        PlatformCache {
+            settings,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// get returns the remembered result for an image, if there is one and it hasn't expired.
+    pub fn get(&self, image: &str) -> Option<Result<ImagePlatforms, LookupError>> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(image) {
+            if entry.expires > now {
+                entry.last_used = now;
+                return Some(entry.value.clone());
+            }
+            if entry.stale_until > now {
+                return None;
+            }
+            entries.remove(image);
+        }
+        None
+    }
+
+    /// get_stale returns an expired successful lookup that is still within its stale window.  The boolean is true
+    /// for exactly one caller at a time, which is then responsible for revalidating the entry and reporting back with
+    /// insert, refresh or revalidation_failed.
+    pub fn get_stale(&self, image: &str) -> Option<(ImagePlatforms, bool)> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(image)?;
+        if entry.expires > now || entry.stale_until <= now {
+            return None;
+        }
+        let platforms = match &entry.value {
+            Ok(p) => p.clone(),
+            Err(_) => return None,
+        };
+        entry.last_used = now;
+        let revalidate = !entry.revalidating;
+        entry.revalidating = true;
+        Some((platforms, revalidate))
+    }
+
+    /// refresh marks an entry as checked and still correct, restarting its ttl without touching the value.
+    pub fn refresh(&self, image: &str) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(image) {
+            entry.expires = now + Duration::from_secs(self.settings.ttl_seconds);
+            entry.stale_until = entry.expires + Duration::from_secs(self.settings.stale_seconds);
+            entry.revalidating = false;
+        }
+    }
+
+    /// revalidation_failed keeps serving the stale entry and lets the next caller try revalidating it again.
+    pub fn revalidation_failed(&self, image: &str) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(image) {
+            entry.revalidating = false;
+        }
+    }
+
+    /// insert remembers a lookup result, making room first if the cache is full.
+    pub fn insert(&self, image: &str, value: Result<ImagePlatforms, LookupError>) {
+        let (ttl, stale) = match &value {
+            Ok(_) => (self.settings.ttl_seconds, self.settings.stale_seconds),
+            Err(e) if e.is_transient() => {
+                debug!("not caching transient lookup failure for {image}: {e}");
+                return;
+            }
+            Err(_) => (self.settings.negative_ttl_seconds, 0),
+        };
+        if self.settings.max_entries == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(image) && entries.len() >= self.settings.max_entries {
+            entries.retain(|_, e| e.stale_until > now);
+        }
+        while !entries.contains_key(image) && entries.len() >= self.settings.max_entries {
+            // still full of live entries, so the one that went unused the longest has to go.
+            let oldest = match entries.iter().min_by_key(|(_, e)| e.last_used) {
+                Some((k, _)) => k.clone(),
+                None => break,
+            };
+            entries.remove(&oldest);
+        }
+        let expires = now + Duration::from_secs(ttl);
+        entries.insert(
+            image.to_string(),
+            CacheEntry {
+                value,
+                expires,
+                stale_until: expires + Duration::from_secs(stale),
+                last_used: now,
+                revalidating: false,
+            },
+        );
+    }
+
+    // the size of the cache only matters to the tests.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
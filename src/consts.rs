@@ -1,7 +1,15 @@
 pub const APP_NAME: &str = "tolerable";
-pub const DOCKER_IMAGE_REGEXP: &str = r#"^(?P<repository>[\w.\-_]+((?::\d+|)(?=/[a-z0-9._-]+/[a-z0-9._-]+))|)\(?:/|)(?P<image>[a-z0-9.\-_]+(?:/[a-z0-9.\-_]+|))(:(?P<tag>[\w.\-_]{1,127})|)$"#;
-pub const TOKEN_AUTH_REGEXP: &str = r#"^Bearer realm="(?P<url>.+)",service="(?P<service>.+)",scope="(?P<scope>.+)"$"#;
 
 // amusingly enough, it seems that docker.io is the only registry allowed to have a different actual dns name,
 // and everyone just seems to go along with this.
 pub const ACTUAL_DOCKER_REGISTRY: &str = r#"registry-1.docker.io"#;
+
+// manifest media types we ask registries for.  The first two describe multi-platform indexes, the second two are
+// single-platform image manifests whose platform lives in the config blob they point at.
+pub const OCI_IMAGE_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+pub const DOCKER_MANIFEST_LIST_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+pub const OCI_IMAGE_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const DOCKER_MANIFEST_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+// how many image lookups one admission request runs at once, unless lookup_parallelism says otherwise.
+pub const DEFAULT_LOOKUP_PARALLELISM: usize = 8;
@@ -0,0 +1,274 @@
+use crate::credentials::{run_plugin, RegistryCredential};
+use crate::read_setting_string;
+use actix_web::web;
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const REQUEST_KIND: &str = "CredentialProviderRequest";
+const RESPONSE_KIND: &str = "CredentialProviderResponse";
+
+lazy_static! {
+    pub static ref CREDENTIAL_PROVIDERS: CredentialProviders = CredentialProviders::from_settings();
+}
+
+/// CredentialProviderConfig is the kubelet's `--image-credential-provider-config` file.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct CredentialProviderConfig {
+    pub providers: Vec<CredentialProvider>,
+}
+
+/// CredentialProvider is one exec plugin, and the images it has credentials for.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CredentialProvider {
+    /// name is the plugin's binary in the plugin directory
+    pub name: String,
+    /// match_images are globs like `*.dkr.ecr.*.amazonaws.com` for the images the plugin is run for
+    pub match_images: Vec<String>,
+    /// default_cache_duration is how long a response is cached when it doesn't say, e.g. `12h`
+    pub default_cache_duration: Option<String>,
+    /// api_version of the request and response, e.g. `credentialprovider.kubelet.k8s.io/v1`
+    pub api_version: String,
+    pub args: Vec<String>,
+    pub env: Vec<ExecEnvVar>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+pub struct ExecEnvVar {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CredentialProviderRequest<'a> {
+    api_version: &'a str,
+    kind: &'a str,
+    image: &'a str,
+}
+
+/// CredentialProviderResponse is what a plugin prints: credentials for the images matching each `auth` key, and
+/// how long and for what they may be reused.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CredentialProviderResponse {
+    pub api_version: String,
+    pub kind: String,
+    /// cache_key_type is `Image`, `Registry` or `Global`
+    pub cache_key_type: String,
+    pub cache_duration: Option<String>,
+    pub auth: HashMap<String, AuthConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub username: String,
+    pub password: String,
+}
+
+impl CredentialProviderConfig {
+    /// parse reads a CredentialProviderConfig, in yaml or json, and checks that every provider can be run.
+    pub fn parse(text: &str) -> anyhow::Result<CredentialProviderConfig> {
+        let config: CredentialProviderConfig =
+            serde_yaml::from_str(text).context("not a valid CredentialProviderConfig")?;
+        for provider in &config.providers {
+            if provider.name.is_empty() || provider.name.contains('/') {
+                bail!("provider name '{}' is not a file name", provider.name);
+            }
+            if provider.match_images.is_empty() {
+                bail!("provider {} has no matchImages", provider.name);
+            }
+            if provider.api_version.is_empty() {
+                bail!("provider {} has no apiVersion", provider.name);
+            }
+            if let Some(d) = &provider.default_cache_duration {
+                parse_go_duration(d).with_context(|| format!("provider {} defaultCacheDuration", provider.name))?;
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// CredentialProviders runs kubelet credential provider plugins, and caches what they said the way the kubelet does.
+pub struct CredentialProviders {
+    providers: Vec<CredentialProvider>,
+    bin_dir: PathBuf,
+    // (provider, cache key) -> response, expiry
+    cache: Mutex<HashMap<(String, String), (CredentialProviderResponse, Instant)>>,
+}
+
+impl CredentialProviders {
+    pub fn new(config: CredentialProviderConfig, bin_dir: &Path) -> CredentialProviders {
+        CredentialProviders {
+            providers: config.providers,
+            bin_dir: bin_dir.to_path_buf(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// from_settings reads the file at `credential_provider_config`, with plugins in `credential_provider_bin_dir`.
+    /// Without the setting there are no plugins, and a file that can't be read is logged and ignored.
+    pub fn from_settings() -> CredentialProviders {
+        let bin_dir = read_setting_string("credential_provider_bin_dir").unwrap_or_default();
+        let config = match read_setting_string("credential_provider_config") {
+            Ok(path) => match fs::read_to_string(&path)
+                .with_context(|| format!("cannot read {path}"))
+                .and_then(|text| CredentialProviderConfig::parse(&text))
+            {
+                Ok(config) => config,
+                Err(e) => {
+                    error!("ignoring credential provider config {path}: {e:#}");
+                    CredentialProviderConfig::default()
+                }
+            },
+            Err(_) => CredentialProviderConfig::default(),
+        };
+        CredentialProviders::new(config, Path::new(&bin_dir))
+    }
+
+    /// credentials finds credentials for `image`, e.g. `123456789012.dkr.ecr.us-east-1.amazonaws.com/app`, from the
+    /// first plugin whose `matchImages` match it and that has any.
+    pub async fn credentials(&self, image: &str) -> anyhow::Result<Option<RegistryCredential>> {
+        for provider in self.providers.iter().filter(|p| p.match_images.iter().any(|g| image_matches(g, image))) {
+            let response = match self.cached(&provider.name, image) {
+                Some(response) => response,
+                None => self.run(provider, image).await.with_context(|| format!("credential provider {}", provider.name))?,
+            };
+            if let Some(credential) = response_credential(&response, image) {
+                return Ok(Some(credential));
+            }
+        }
+        Ok(None)
+    }
+
+    /// cached looks for a response about the image itself, then its registry, then one for every image.
+    fn cached(&self, provider: &str, image: &str) -> Option<CredentialProviderResponse> {
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (_, expires)| *expires > now);
+        [image, registry_of(image), ""]
+            .iter()
+            .find_map(|key| cache.get(&(provider.to_string(), key.to_string())))
+            .map(|(response, _)| response.clone())
+    }
+
+    async fn run(&self, provider: &CredentialProvider, image: &str) -> anyhow::Result<CredentialProviderResponse> {
+        let mut command = Command::new(self.bin_dir.join(&provider.name));
+        command.args(&provider.args).envs(provider.env.iter().map(|e| (&e.name, &e.value)));
+        let request = serde_json::to_vec(&CredentialProviderRequest {
+            api_version: &provider.api_version,
+            kind: REQUEST_KIND,
+            image,
+        })?;
+        let output = web::block(move || run_plugin(command, &request)).await.map_err(|e| anyhow!("{e}"))??;
+        if !output.status.success() {
+            bail!("failed ({}): {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+        }
+        let response: CredentialProviderResponse =
+            serde_json::from_slice(&output.stdout).context("sent an invalid CredentialProviderResponse")?;
+        if response.kind != RESPONSE_KIND || response.api_version != provider.api_version {
+            bail!("sent kind '{}' of '{}', not {RESPONSE_KIND} of '{}'", response.kind, response.api_version, provider.api_version);
+        }
+        let key = match response.cache_key_type.as_str() {
+            "Image" => image,
+            "Registry" => registry_of(image),
+            "Global" => "",
+            other => bail!("sent unknown cacheKeyType '{other}'"),
+        };
+        let duration = match response.cache_duration.as_ref().or(provider.default_cache_duration.as_ref()) {
+            Some(d) => parse_go_duration(d).unwrap_or_else(|e| {
+                warn!("not caching what credential provider {} said: {e:#}", provider.name);
+                Duration::ZERO
+            }),
+            None => Duration::ZERO,
+        };
+        if let Some(expires) = Instant::now().checked_add(duration).filter(|_| !duration.is_zero()) {
+            let mut cache = self.cache.lock().unwrap();
+            cache.insert((provider.name.clone(), key.to_string()), (response.clone(), expires));
+        }
+        Ok(response)
+    }
+}
+
+/// response_credential picks the most specific of a response's `auth` entries that matches `image`.
+fn response_credential(response: &CredentialProviderResponse, image: &str) -> Option<RegistryCredential> {
+    let mut matching: Vec<(&String, &AuthConfig)> =
+        response.auth.iter().filter(|(glob, _)| image_matches(glob, image)).collect();
+    matching.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(b.0)));
+    matching.first().map(|(_, auth)| RegistryCredential::Basic {
+        user: auth.username.clone(),
+        secret: auth.password.clone(),
+    })
+}
+
+fn registry_of(image: &str) -> &str {
+    image.split('/').next().unwrap_or(image)
+}
+
+/// image_matches applies a kubelet `matchImages` glob to an image: the hosts must have as many dot-separated parts,
+/// each matching its glob, the ports must be the same, and the glob's path must be a prefix of the image's.
+pub fn image_matches(glob: &str, image: &str) -> bool {
+    let strip_scheme = |s: &str| s.trim_start_matches("https://").trim_start_matches("http://").to_string();
+    let (glob, image) = (strip_scheme(glob), strip_scheme(image));
+    let (glob_host, glob_path) = glob.split_once('/').unwrap_or((glob.as_str(), ""));
+    let (image_host, image_path) = image.split_once('/').unwrap_or((image.as_str(), ""));
+    let (glob_host, glob_port) = glob_host.split_once(':').unwrap_or((glob_host, ""));
+    let (image_host, image_port) = image_host.split_once(':').unwrap_or((image_host, ""));
+    if glob_port != image_port {
+        return false;
+    }
+    let glob_parts: Vec<&str> = glob_host.split('.').collect();
+    let image_parts: Vec<&str> = image_host.split('.').collect();
+    glob_parts.len() == image_parts.len()
+        && glob_parts.iter().zip(&image_parts).all(|(g, i)| glob_match(g.as_bytes(), i.as_bytes()))
+        && image_path.starts_with(glob_path)
+}
+
+/// glob_match matches `*` (any run of characters) and `?` (any one character).
+fn glob_match(glob: &[u8], s: &[u8]) -> bool {
+    match glob.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|i| glob_match(rest, &s[i..])),
+        Some((b'?', rest)) => !s.is_empty() && glob_match(rest, &s[1..]),
+        Some((c, rest)) => s.first() == Some(c) && glob_match(rest, &s[1..]),
+    }
+}
+
+/// parse_go_duration reads a duration as go formats them, e.g. `12h`, `1h30m` or `6h0m0s`.
+pub fn parse_go_duration(text: &str) -> anyhow::Result<Duration> {
+    let invalid = || anyhow!("invalid duration '{text}'");
+    if text == "0" {
+        return Ok(Duration::ZERO);
+    }
+    let mut rest = text;
+    let mut seconds = 0f64;
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let number_end = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+        let number: f64 = rest[..number_end].parse().map_err(|_| invalid())?;
+        rest = &rest[number_end..];
+        let unit_end = rest.find(|c: char| c.is_ascii_digit() || c == '.').unwrap_or(rest.len());
+        let unit = match &rest[..unit_end] {
+            "ns" => 1e-9,
+            "us" | "µs" => 1e-6,
+            "ms" => 1e-3,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return Err(invalid()),
+        };
+        rest = &rest[unit_end..];
+        seconds += number * unit;
+    }
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid())
+}
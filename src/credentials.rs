@@ -0,0 +1,530 @@
+use crate::auth::{AuthSettings, RegistryAuth};
+use crate::credential_provider::CREDENTIAL_PROVIDERS;
+use crate::errors::LookupError;
+use crate::{read_setting_string, SETTINGS};
+use actix_web::web;
+use anyhow::{anyhow, bail, Context};
+use base64::{engine::general_purpose, Engine as _};
+use config::Config;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+// a credential helper or plugin that hasn't answered by now isn't going to.
+const PLUGIN_TIMEOUT_SECONDS: u64 = 10;
+// docker hub is known to credential helpers by its old index url.
+const DOCKER_HUB_SERVER_URL: &str = "https://index.docker.io/v1/";
+
+lazy_static! {
+    pub static ref HELPER_CACHE: CredentialCache =
+        CredentialCache::new(Duration::from_secs(AuthSettings::from_settings().credential_helper_ttl_seconds));
+    // parsed docker configs by path, with the modification time of the file they were parsed from.
+    static ref DOCKER_CONFIGS: Mutex<HashMap<String, (SystemTime, DockerConfig)>> = Mutex::new(HashMap::new());
+}
+
+/// RegistryCredential is what we prove who we are to a registry with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegistryCredential {
+    /// a user and password, sent to token services and to registries that want basic auth
+    Basic { user: String, secret: String },
+    /// an oauth2 refresh token (`identitytoken` in a docker config), traded for access tokens at the token service
+    IdentityToken(String),
+    /// a bearer token sent to the registry as is (`registrytoken` in a docker config)
+    RegistryToken(String),
+}
+
+impl RegistryCredential {
+    /// basic_auth is the credential as basic auth, if it is a user and password.
+    pub fn basic_auth(&self) -> Option<RegistryAuth> {
+        match self {
+            RegistryCredential::Basic { user, secret } => Some(RegistryAuth::Basic {
+                user: user.clone(),
+                secret: secret.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// fingerprint tells credentials apart without giving them away, so what was fetched with one credential is
+    /// never handed to a lookup that has another.
+    pub fn fingerprint(&self) -> String {
+        let text = match self {
+            RegistryCredential::Basic { user, secret } => format!("basic\0{user}\0{secret}"),
+            RegistryCredential::IdentityToken(token) => format!("identity\0{token}"),
+            RegistryCredential::RegistryToken(token) => format!("registry\0{token}"),
+        };
+        format!("{:x}", Sha256::digest(text.as_bytes()))[..16].to_string()
+    }
+}
+
+/// PullSecrets are the docker configs of the imagePullSecrets of the pod being admitted, in the order the pod lists
+/// them, each with the `<namespace>/<name>` of its secret.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PullSecrets {
+    configs: Vec<(String, DockerConfig)>,
+}
+
+impl PullSecrets {
+    pub fn new(configs: Vec<(String, DockerConfig)>) -> PullSecrets {
+        PullSecrets { configs }
+    }
+
+    /// credential_for finds the credential for `repository` on `registry`, as the kubelet does: the entry for the
+    /// longest repository prefix in any of the secrets, and otherwise the first secret with one for the registry.
+    pub fn credential_for(&self, registry: &str, repository: &str) -> Result<Option<RegistryCredential>, LookupError> {
+        for prefix in repository_prefixes(repository) {
+            if let Some(cred) = configs_credential(&self.configs, registry, |c| c.auth_for_prefix(registry, prefix))? {
+                return Ok(Some(cred));
+            }
+        }
+        configs_credential(&self.configs, registry, |c| c.auth_for(registry))
+    }
+}
+
+/// get_credentials_for_registry finds credentials for `repository` on `registry`.  Credentials bound to the
+/// longest prefix of the repository win, from `<registry_credential_path>/<registry>/<prefix>.toml` or a
+/// `docker_config_files` entry like `ghcr.io/team-a`.  Then come the registry's own: `credential_file` if it has
+/// one, else `<registry>.toml`, then the first of the `docker_config_files` with an entry for it, and the kubelet
+/// credential provider plugins that match the image.  Ok(None) means there are no credentials for this repository,
+/// which is fine for public images.
+pub async fn get_credentials_for_registry(
+    registry: String,
+    repository: String,
+    credential_file: Option<String>,
+) -> Result<Option<RegistryCredential>, LookupError> {
+    let cred_path = read_setting_string("registry_credential_path").ok();
+    let docker_config_files: Vec<String> = SETTINGS
+        .read()
+        .unwrap()
+        .get::<Vec<String>>("docker_config_files")
+        .unwrap_or_default();
+    let configs = load_docker_configs(&docker_config_files, &registry)?;
+    for prefix in repository_prefixes(&repository) {
+        if let Some(cred_path) = &cred_path {
+            let cred_file = format!("{}/{}/{}.toml", cred_path, registry, prefix);
+            if let Some(cred) = read_credential_file(&registry, &cred_file)? {
+                return Ok(Some(cred));
+            }
+        }
+        if let Some(cred) = configs_credential(&configs, &registry, |c| c.auth_for_prefix(&registry, prefix))? {
+            return Ok(Some(cred));
+        }
+    }
+    let cred_file = credential_file.or_else(|| cred_path.map(|p| format!("{}/{}.toml", p, registry)));
+    if let Some(cred_file) = cred_file {
+        if let Some(cred) = read_credential_file(&registry, &cred_file)? {
+            return Ok(Some(cred));
+        }
+    }
+    if let Some(cred) = docker_config_credentials(&configs, &registry).await? {
+        return Ok(Some(cred));
+    }
+    CREDENTIAL_PROVIDERS
+        .credentials(&format!("{registry}/{repository}"))
+        .await
+        .map_err(|e| LookupError::Credentials { registry, message: format!("{e:#}") })
+}
+
+/// repository_prefixes are the prefixes of a repository that credentials can be bound to, longest first:
+/// `team-a/tools/app` has `team-a/tools/app`, `team-a/tools` and `team-a`.
+pub fn repository_prefixes(repository: &str) -> Vec<&str> {
+    let repository = repository.trim_matches('/');
+    let mut prefixes: Vec<&str> = repository.match_indices('/').map(|(i, _)| &repository[..i]).collect();
+    if !repository.is_empty() {
+        prefixes.push(repository);
+    }
+    prefixes.reverse();
+    prefixes
+}
+
+/// configs_credential is the credential of the entry `find` picks in the first docker config that has one.
+fn configs_credential<'a>(
+    configs: &'a [(String, DockerConfig)],
+    registry: &str,
+    find: impl Fn(&'a DockerConfig) -> Option<&'a DockerAuth>,
+) -> Result<Option<RegistryCredential>, LookupError> {
+    for (source, config) in configs {
+        if let Some(auth) = find(config) {
+            return auth.credential().map_err(|e| LookupError::Credentials {
+                registry: registry.to_string(),
+                message: format!("in {source}: {e:#}"),
+            });
+        }
+    }
+    Ok(None)
+}
+
+/// read_credential_file loads a credential file with `user` and `secret` keys.  Ok(None) means it doesn't exist.
+pub fn read_credential_file(registry: &str, cred_file: &str) -> Result<Option<RegistryCredential>, LookupError> {
+    if !Path::new(&cred_file).exists() {
+        debug!("credential file does not exist: {}", cred_file);
+        return Ok(None);
+    };
+    let cred_error = |message: String| LookupError::Credentials { registry: registry.to_string(), message };
+    let cred: config::Config = match Config::builder().add_source(config::File::with_name(cred_file)).build() {
+        Ok(config) => config,
+        Err(e) => {
+            return Err(cred_error(format!("path '{}' exists but config couldn't initialize: {}", cred_file, e)));
+        }
+    };
+
+    let user: String = match cred.get::<String>("user") {
+        Ok(user) => user,
+        Err(e) => {
+            return Err(cred_error(format!("In '{}', config key 'user' does not exist: {}", cred_file, e)));
+        }
+    };
+    let secret: String = match cred.get::<String>("secret") {
+        Ok(secret) => secret,
+        Err(e) => {
+            return Err(cred_error(format!("In '{}', config key 'secret' does not exist: {}", cred_file, e)));
+        }
+    };
+    Ok(Some(RegistryCredential::Basic { user, secret }))
+}
+
+/// load_docker_configs reads docker config files, keeping their order.  Files that don't exist are skipped, but one
+/// that can't be read is an error, the same as a broken credential file.  A file is only parsed again once it has
+/// been modified.
+pub fn load_docker_configs(files: &[String], registry: &str) -> Result<Vec<(String, DockerConfig)>, LookupError> {
+    let mut configs = Vec::new();
+    for file in files {
+        if !Path::new(file).exists() {
+            debug!("docker config does not exist: {file}");
+            continue;
+        }
+        let modified = fs::metadata(file).and_then(|m| m.modified()).ok();
+        let cached = match (modified, DOCKER_CONFIGS.lock().unwrap().get(file)) {
+            (Some(modified), Some((parsed_at, config))) if *parsed_at == modified => Some(config.clone()),
+            _ => None,
+        };
+        let config = match cached {
+            Some(config) => config,
+            None => {
+                let config = fs::read_to_string(file)
+                    .with_context(|| format!("cannot read {file}"))
+                    .and_then(|text| DockerConfig::parse(&text).with_context(|| format!("in {file}")))
+                    .map_err(|e| LookupError::Credentials {
+                        registry: registry.to_string(),
+                        message: format!("{e:#}"),
+                    })?;
+                if let Some(modified) = modified {
+                    DOCKER_CONFIGS.lock().unwrap().insert(file.clone(), (modified, config.clone()));
+                }
+                config
+            }
+        };
+        configs.push((file.clone(), config));
+    }
+    Ok(configs)
+}
+
+/// docker_config_credentials looks `registry` up in docker configs, in order, running a credential helper if the
+/// config says to.
+pub async fn docker_config_credentials(
+    configs: &[(String, DockerConfig)],
+    registry: &str,
+) -> Result<Option<RegistryCredential>, LookupError> {
+    let cred_error = |message: String| LookupError::Credentials { registry: registry.to_string(), message };
+    for (file, config) in configs {
+        match config.source_for(registry) {
+            Some(CredentialSource::Auth(auth)) => {
+                return auth.credential().map_err(|e| cred_error(format!("in {file}: {e:#}")));
+            }
+            Some(CredentialSource::Helper(helper)) => {
+                return helper_credentials(&helper, registry)
+                    .await
+                    .map_err(|e| cred_error(format!("credential helper {helper}: {e:#}")));
+            }
+            None => {}
+        }
+    }
+    Ok(None)
+}
+
+/// CredentialSource is where a docker config says a registry's credentials are.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CredentialSource<'a> {
+    /// an entry of its `auths`
+    Auth(&'a DockerAuth),
+    /// a `docker-credential-<helper>` binary
+    Helper(String),
+}
+
+/// DockerConfig is a docker `config.json`, which is also what a `kubernetes.io/dockerconfigjson` secret holds.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct DockerConfig {
+    pub auths: HashMap<String, DockerAuth>,
+    /// cred_helpers name the credential helper of particular registries
+    #[serde(rename = "credHelpers")]
+    pub cred_helpers: HashMap<String, String>,
+    /// creds_store is the credential helper of every other registry
+    #[serde(rename = "credsStore")]
+    pub creds_store: Option<String>,
+}
+
+/// DockerAuth is one entry of a docker config's `auths`.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct DockerAuth {
+    /// auth is `user:password`, base64 encoded
+    pub auth: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub identitytoken: Option<String>,
+    pub registrytoken: Option<String>,
+}
+
+impl DockerConfig {
+    /// parse reads a config.json.  The older `.dockercfg` format, which is just the `auths` object, is accepted too.
+    pub fn parse(text: &str) -> anyhow::Result<DockerConfig> {
+        let json: Value = serde_json::from_str(text).context("not valid json")?;
+        let legacy = match json.as_object() {
+            Some(o) => !o.contains_key("auths") && !o.is_empty() && o.values().all(|v| v.get("auth").is_some()),
+            None => false,
+        };
+        if legacy {
+            let auths = serde_json::from_value(json).context("not a valid .dockercfg")?;
+            return Ok(DockerConfig { auths, ..DockerConfig::default() });
+        }
+        serde_json::from_value(json).context("not a valid docker config")
+    }
+
+    /// source_for says where the credentials for `registry` are, in the order docker looks: its own credential
+    /// helper, then the credential store, then `auths`.
+    pub fn source_for(&self, registry: &str) -> Option<CredentialSource<'_>> {
+        let host = docker_hostname(registry);
+        let mut helpers: Vec<(&String, &String)> =
+            self.cred_helpers.iter().filter(|(k, _)| docker_hostname(k) == host).collect();
+        helpers.sort();
+        if let Some((_, helper)) = helpers.first() {
+            return Some(CredentialSource::Helper(helper.to_string()));
+        }
+        if let Some(store) = self.creds_store.as_ref().filter(|s| !s.is_empty()) {
+            return Some(CredentialSource::Helper(store.clone()));
+        }
+        self.auth_for(registry).map(CredentialSource::Auth)
+    }
+
+    /// auth_for finds the entry for `registry` as a whole.  Keys are compared the way docker compares them, so
+    /// `https://index.docker.io/v1/` is docker hub and `https://quay.io/v2/` is `quay.io`.  A key spelled exactly
+    /// like the registry wins over others that only normalize to it.
+    pub fn auth_for(&self, registry: &str) -> Option<&DockerAuth> {
+        if let Some(auth) = self.auths.get(registry) {
+            return Some(auth);
+        }
+        self.auth_for_prefix(registry, "")
+    }
+
+    /// auth_for_prefix finds the entry bound to repositories under `prefix` on `registry`, e.g. the one keyed
+    /// `ghcr.io/team-a/` for `team-a`.
+    pub fn auth_for_prefix(&self, registry: &str, prefix: &str) -> Option<&DockerAuth> {
+        let host = docker_hostname(registry);
+        let mut keys: Vec<&String> = self
+            .auths
+            .keys()
+            .filter(|k| docker_hostname(k) == host && docker_path(k) == prefix)
+            .collect();
+        keys.sort();
+        keys.first().map(|k| &self.auths[*k])
+    }
+}
+
+impl DockerAuth {
+    /// credential is what the entry says to authenticate with.  A registry token wins over an identity token,
+    /// which wins over a user and password.  Ok(None) means the entry is empty.
+    pub fn credential(&self) -> anyhow::Result<Option<RegistryCredential>> {
+        if let Some(token) = self.registrytoken.as_ref().filter(|t| !t.is_empty()) {
+            return Ok(Some(RegistryCredential::RegistryToken(token.clone())));
+        }
+        if let Some(token) = self.identitytoken.as_ref().filter(|t| !t.is_empty()) {
+            return Ok(Some(RegistryCredential::IdentityToken(token.clone())));
+        }
+        if let (Some(user), Some(secret)) = (&self.username, &self.password) {
+            if !user.is_empty() {
+                return Ok(Some(RegistryCredential::Basic { user: user.clone(), secret: secret.clone() }));
+            }
+        }
+        let auth = match self.auth.as_ref().filter(|a| !a.is_empty()) {
+            Some(a) => a,
+            None => return Ok(None),
+        };
+        let decoded = general_purpose::STANDARD.decode(auth.trim()).context("auth is not valid base64")?;
+        let decoded = String::from_utf8(decoded).context("auth is not valid utf-8")?;
+        match decoded.split_once(':') {
+            Some((user, secret)) => Ok(Some(RegistryCredential::Basic { user: user.to_string(), secret: secret.to_string() })),
+            None => Err(anyhow!("auth is not in user:password form")),
+        }
+    }
+}
+
+/// docker_hostname is the registry host a docker config key or registry name refers to: no scheme, no path, and
+/// docker hub's many names all folded into `docker.io`.
+pub fn docker_hostname(key: &str) -> String {
+    let key = key.to_lowercase();
+    let host = key.trim_start_matches("https://").trim_start_matches("http://");
+    match host.split('/').next().unwrap_or("") {
+        "index.docker.io" | "registry-1.docker.io" | "docker.io" => "docker.io".to_string(),
+        h => h.to_string(),
+    }
+}
+
+/// docker_path is the repository prefix a docker config key is bound to, e.g. `team-a` for `ghcr.io/team-a/`.  It
+/// is empty for keys that are a whole registry, including docker's `https://<host>/v1/` and `/v2/` urls.
+pub fn docker_path(key: &str) -> String {
+    let key = key.trim_start_matches("https://").trim_start_matches("http://");
+    match key.split_once('/').map(|(_, path)| path.trim_matches('/')) {
+        None | Some("v1") | Some("v2") => String::new(),
+        Some(path) => path.to_string(),
+    }
+}
+
+/// CredentialCache remembers what credential helpers said, so a helper isn't run for every lookup.
+pub struct CredentialCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Option<RegistryCredential>, Instant)>>,
+}
+
+impl CredentialCache {
+    pub fn new(ttl: Duration) -> CredentialCache {
+        CredentialCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// get returns what `helper` said about `registry`, unless that was too long ago.  Some(None) means it had
+    /// nothing for the registry.
+    pub fn get(&self, helper: &str, registry: &str) -> Option<Option<RegistryCredential>> {
+        let key = format!("{helper} {registry}");
+        let mut entries = self.entries.lock().unwrap();
+        if let Some((credential, expires)) = entries.get(&key) {
+            if *expires > Instant::now() {
+                return Some(credential.clone());
+            }
+            entries.remove(&key);
+        }
+        None
+    }
+
+    pub fn insert(&self, helper: &str, registry: &str, credential: Option<RegistryCredential>) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (_, expires)| *expires > now);
+        entries.insert(format!("{helper} {registry}"), (credential, now + self.ttl));
+    }
+}
+
+/// helper_credentials asks a credential helper for the credentials of `registry`, by way of HELPER_CACHE.  The
+/// helper runs on the blocking thread pool, so a slow one doesn't hold up the worker.
+async fn helper_credentials(helper: &str, registry: &str) -> anyhow::Result<Option<RegistryCredential>> {
+    if let Some(credential) = HELPER_CACHE.get(helper, registry) {
+        return Ok(credential);
+    }
+    let program = format!("docker-credential-{helper}");
+    let server_url = match docker_hostname(registry).as_str() {
+        "docker.io" => DOCKER_HUB_SERVER_URL.to_string(),
+        host => host.to_string(),
+    };
+    let credential = web::block(move || run_credential_helper(&program, &server_url))
+        .await
+        .map_err(|e| anyhow!("{e}"))??;
+    HELPER_CACHE.insert(helper, registry, credential.clone());
+    Ok(credential)
+}
+
+/// HelperResponse is what a credential helper prints for `get`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperResponse {
+    username: String,
+    secret: String,
+}
+
+/// run_credential_helper runs `<program> get` with the server url on stdin, as the docker credential helper
+/// protocol has it.  Ok(None) means the helper has no credentials for the server.
+pub fn run_credential_helper(program: &str, server_url: &str) -> anyhow::Result<Option<RegistryCredential>> {
+    let mut command = Command::new(program);
+    command.arg("get");
+    let output = run_plugin(command, server_url.as_bytes())?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        let message = format!("{}{}", stdout.trim(), String::from_utf8_lossy(&output.stderr).trim());
+        // the message every helper uses for servers it knows nothing about.
+        if message.contains("credentials not found") {
+            return Ok(None);
+        }
+        bail!("{program} failed ({}): {message}", output.status);
+    }
+    let response: HelperResponse = serde_json::from_str(&stdout).with_context(|| format!("{program} sent invalid json"))?;
+    if response.username == "<token>" {
+        return Ok(Some(RegistryCredential::IdentityToken(response.secret)));
+    }
+    Ok(Some(RegistryCredential::Basic { user: response.username, secret: response.secret }))
+}
+
+/// run_plugin runs `command` with `input` on stdin and collects what it prints, killing it if it doesn't finish in
+/// time.  Credential helpers and kubelet credential provider plugins both work this way.
+pub(crate) fn run_plugin(mut command: Command, input: &[u8]) -> anyhow::Result<Output> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("cannot run {program}"))?;
+    // the pipes are fed and drained on threads of their own, so a plugin with a lot to say never fills one up and
+    // stalls while we wait for it.
+    let stdin = child.stdin.take().map(|mut stdin| {
+        let input = input.to_vec();
+        // a plugin that exits without reading says why on stdout or stderr, which beats a broken pipe.
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        })
+    });
+    let stdout = child.stdout.take().map(read_pipe);
+    let stderr = child.stderr.take().map(read_pipe);
+    let deadline = Instant::now() + Duration::from_secs(PLUGIN_TIMEOUT_SECONDS);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("{program} did not answer within {PLUGIN_TIMEOUT_SECONDS} seconds");
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    if let Some(stdin) = stdin {
+        let _ = stdin.join();
+    }
+    let collect = |pipe: Option<JoinHandle<io::Result<Vec<u8>>>>| -> anyhow::Result<Vec<u8>> {
+        match pipe {
+            Some(reader) => Ok(reader.join().map_err(|_| anyhow!("reading from {program} panicked"))??),
+            None => Ok(Vec::new()),
+        }
+    };
+    Ok(Output { status, stdout: collect(stdout)?, stderr: collect(stderr)? })
+}
+
+/// read_pipe reads everything from one of a plugin's output pipes on a thread of its own.
+fn read_pipe(mut pipe: impl Read + Send + 'static) -> JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        pipe.read_to_end(&mut buffer)?;
+        Ok(buffer)
+    })
+}
@@ -0,0 +1,107 @@
+use thiserror::Error;
+
+/// LookupError explains why the platforms of an image couldn't be determined.  Errors that come from talking to a
+/// registry carry the registry and repository, so logs and admission warnings can point at the image at fault.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum LookupError {
+    #[error("'{image}' is not a valid image reference: {message}")]
+    InvalidReference { image: String, message: String },
+    #[error("{registry}/{repository} was not found (HTTP {status})")]
+    NotFound {
+        registry: String,
+        repository: String,
+        status: u16,
+    },
+    #[error("not authorized to read {registry}/{repository} (HTTP {status})")]
+    Unauthorized {
+        registry: String,
+        repository: String,
+        status: u16,
+    },
+    #[error("registry {registry} answered HTTP {status} for {repository}")]
+    Http {
+        registry: String,
+        repository: String,
+        status: u16,
+    },
+    #[error("unable to reach registry {registry}: {message}")]
+    Unreachable { registry: String, message: String },
+    #[error("registry {registry} sent unsupported media type '{media_type}' for {repository}")]
+    UnsupportedMediaType {
+        registry: String,
+        repository: String,
+        media_type: String,
+    },
+    #[error("registry {registry} sent an invalid response for {repository}: {message}")]
+    InvalidResponse {
+        registry: String,
+        repository: String,
+        message: String,
+    },
+    #[error("content from {registry}/{repository} does not match digest {digest}")]
+    DigestMismatch {
+        registry: String,
+        repository: String,
+        digest: String,
+    },
+    #[error("{registry}/{repository} does not describe any usable platform")]
+    NoPlatforms { registry: String, repository: String },
+    #[error("unable to authenticate to registry {registry}: {message}")]
+    Auth { registry: String, message: String },
+    #[error("unable to load credentials for registry {registry}: {message}")]
+    Credentials { registry: String, message: String },
+    #[error("lookups from registry {registry} are disabled")]
+    RegistryDisabled { registry: String },
+}
+
+impl LookupError {
+    /// from_status turns an unsuccessful HTTP status from a registry into the matching error.
+    pub fn from_status(registry: &str, repository: &str, status: u16) -> LookupError {
+        let registry = registry.to_string();
+        let repository = repository.to_string();
+        match status {
+            401 | 403 => LookupError::Unauthorized {
+                registry,
+                repository,
+                status,
+            },
+            404 => LookupError::NotFound {
+                registry,
+                repository,
+                status,
+            },
+            _ => LookupError::Http {
+                registry,
+                repository,
+                status,
+            },
+        }
+    }
+
+    /// is_transient says whether the same lookup is likely to succeed if it is simply tried again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            LookupError::Unreachable { .. } => true,
+            LookupError::Http { status, .. } => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+
+    /// kind is a short, stable name for the error, used as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LookupError::InvalidReference { .. } => "invalid_reference",
+            LookupError::NotFound { .. } => "not_found",
+            LookupError::Unauthorized { .. } => "unauthorized",
+            LookupError::Http { .. } => "http_status",
+            LookupError::Unreachable { .. } => "unreachable",
+            LookupError::UnsupportedMediaType { .. } => "unsupported_media_type",
+            LookupError::InvalidResponse { .. } => "invalid_response",
+            LookupError::DigestMismatch { .. } => "digest_mismatch",
+            LookupError::NoPlatforms { .. } => "no_platforms",
+            LookupError::Auth { .. } => "auth",
+            LookupError::Credentials { .. } => "credentials",
+            LookupError::RegistryDisabled { .. } => "registry_disabled",
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use crate::registry::registry_host;
+use crate::tls::TlsFiles;
+use anyhow::{bail, Context};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// HostsFile is what tolerable uses of a containerd `certs.d/<host>/hosts.toml`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct HostsFile {
+    /// server is the upstream registry, when it isn't `https://<host>`
+    pub server: Option<String>,
+    /// server_tls are the certificates for talking to the upstream registry
+    pub server_tls: TlsFiles,
+    /// hosts are the mirrors, in the order containerd tries them
+    pub hosts: Vec<HostEntry>,
+}
+
+/// HostEntry is one `[host."<url>"]` table of a hosts.toml.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostEntry {
+    /// url is the mirror, e.g. `https://harbor.example.com/v2/dockerhub-proxy`
+    pub url: String,
+    /// capabilities are what the mirror may be used for: `pull`, `resolve` and `push`
+    pub capabilities: Vec<String>,
+    /// override_path says url is already the api root, so `/v2` isn't added to it
+    pub override_path: bool,
+    /// tls are the certificates for talking to the mirror
+    pub tls: TlsFiles,
+}
+
+impl HostEntry {
+    /// can_pull says whether manifests may be fetched from the mirror by digest.
+    pub fn can_pull(&self) -> bool {
+        self.capabilities.iter().any(|c| c == "pull")
+    }
+
+    /// can_resolve says whether tags may be resolved against the mirror.
+    pub fn can_resolve(&self) -> bool {
+        self.capabilities.iter().any(|c| c == "resolve")
+    }
+}
+
+/// hosts_files reads every `<host>/hosts.toml` in a certs.d directory, keyed by registry host.  A missing
+/// directory simply has no registries in it, and a broken file is logged and skipped.
+pub fn hosts_files(dir: &Path) -> HashMap<String, HostsFile> {
+    let mut registries: HashMap<String, HostsFile> = HashMap::new();
+    let hosts = match fs::read_dir(dir) {
+        Ok(h) => h,
+        Err(_) => return registries,
+    };
+    for host in hosts.flatten() {
+        let path = host.path().join("hosts.toml");
+        if !path.is_file() {
+            continue;
+        }
+        let name = registry_host(&host.file_name().to_string_lossy());
+        let parsed = fs::read_to_string(&path)
+            .with_context(|| format!("cannot read {}", path.display()))
+            .and_then(|text| parse_hosts_toml(&text, &host.path()));
+        match parsed {
+            Ok(file) => {
+                registries.insert(name, file);
+            }
+            Err(e) => error!("ignoring {}: {e:#}", path.display()),
+        }
+    }
+    registries
+}
+
+/// parse_hosts_toml parses the contents of a hosts.toml.  Relative certificate paths are relative to `dir`, the
+/// directory the file lives in, as they are for containerd.
+pub fn parse_hosts_toml(text: &str, dir: &Path) -> anyhow::Result<HostsFile> {
+    let root: Value = text.parse().context("not valid toml")?;
+    let mut file = HostsFile {
+        server: root.get("server").and_then(|s| s.as_str()).map(|s| s.to_string()),
+        server_tls: tls_files(&root, dir)?,
+        hosts: vec![],
+    };
+    if let Some(hosts) = root.get("host") {
+        let hosts = match hosts.as_table() {
+            Some(h) => h,
+            None => bail!("host must be a table of mirrors"),
+        };
+        // toml's preserve_order keeps the tables in the order of the file, which is the order mirrors are tried in.
+        for (url, host) in hosts {
+            let capabilities = match host.get("capabilities").and_then(|c| c.as_array()) {
+                Some(c) => c.iter().filter_map(|c| c.as_str()).map(|c| c.to_lowercase()).collect(),
+                None => vec!["pull".to_string(), "resolve".to_string()],
+            };
+            file.hosts.push(HostEntry {
+                url: url.clone(),
+                capabilities,
+                override_path: host.get("override_path").and_then(|o| o.as_bool()).unwrap_or(false),
+                tls: tls_files(host, dir).with_context(|| format!("in host {url}"))?,
+            });
+        }
+    }
+    Ok(file)
+}
+
+/// tls_files reads the `ca` and `client` keys of a hosts.toml table.  `ca` is a path or a list of them, and
+/// `client` is a pem file holding both certificate and key, a `[cert, key]` pair, or a list of such pairs.
+fn tls_files(table: &Value, dir: &Path) -> anyhow::Result<TlsFiles> {
+    let path = |p: &str| -> PathBuf {
+        let p = PathBuf::from(p);
+        if p.is_absolute() {
+            p
+        } else {
+            dir.join(p)
+        }
+    };
+    let mut files = TlsFiles::default();
+    match table.get("ca") {
+        None => {}
+        Some(Value::String(ca)) => files.ca_files.push(path(ca)),
+        Some(Value::Array(cas)) => {
+            for ca in cas {
+                match ca.as_str() {
+                    Some(ca) => files.ca_files.push(path(ca)),
+                    None => bail!("ca must be a path or a list of paths"),
+                }
+            }
+        }
+        Some(_) => bail!("ca must be a path or a list of paths"),
+    }
+    files.client_cert = match table.get("client") {
+        None => None,
+        Some(Value::String(pem)) => Some((path(pem), path(pem))),
+        Some(Value::Array(client)) => {
+            // either a single pair, or a list of pairs of which we can only use the first.
+            let pair = match client.first() {
+                Some(Value::Array(first)) => first,
+                _ => client,
+            };
+            match pair.as_slice() {
+                [] => None,
+                [Value::String(cert), Value::String(key)] => Some((path(cert), path(key))),
+                [Value::String(pem)] => Some((path(pem), path(pem))),
+                _ => bail!("client must be a pem file or a [cert, key] pair"),
+            }
+        }
+        Some(_) => bail!("client must be a pem file or a [cert, key] pair"),
+    };
+    if table.get("skip_verify").and_then(|s| s.as_bool()).unwrap_or(false) {
+        warn!("skip_verify is not supported, tolerable always verifies registry certificates; set ca instead");
+    }
+    Ok(files)
+}
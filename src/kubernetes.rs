@@ -0,0 +1,160 @@
+use crate::credentials::{DockerConfig, PullSecrets};
+use crate::registry::user_agent;
+use crate::tls::{client_config, TlsFiles};
+use crate::SETTINGS;
+use actix_web::http::header;
+use anyhow::{anyhow, bail, Context};
+use awc::{Client, Connector};
+use base64::{engine::general_purpose, Engine as _};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// KubernetesSettings is the `[kubernetes]` table of tolerable.toml.  The defaults are what a pod running in the
+/// cluster is given.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct KubernetesSettings {
+    /// pull_secrets turns on looking images up with the imagePullSecrets of the pod being admitted
+    pub pull_secrets: bool,
+    /// api_server is the url of the kubernetes api
+    pub api_server: String,
+    /// token_file is the service account token sent as a bearer token, read again for every request since it
+    /// is rotated
+    pub token_file: String,
+    /// ca_file is the CA the api server's certificate is checked against
+    pub ca_file: String,
+    /// timeout_seconds is how long the api server may take to answer
+    pub timeout_seconds: u64,
+}
+
+impl Default for KubernetesSettings {
+    fn default() -> Self {
+        KubernetesSettings {
+            pull_secrets: false,
+            api_server: "https://kubernetes.default.svc".to_string(),
+            token_file: "/var/run/secrets/kubernetes.io/serviceaccount/token".to_string(),
+            ca_file: "/var/run/secrets/kubernetes.io/serviceaccount/ca.crt".to_string(),
+            timeout_seconds: 5,
+        }
+    }
+}
+
+impl KubernetesSettings {
+    pub fn from_settings() -> KubernetesSettings {
+        SETTINGS.read().unwrap().get::<KubernetesSettings>("kubernetes").unwrap_or_default()
+    }
+}
+
+/// KubernetesClient reads the secrets and service accounts that pods refer to from the kubernetes api.  Like the
+/// registry client, each worker builds its own.
+#[derive(Clone)]
+pub struct KubernetesClient {
+    client: Client,
+    api_server: String,
+    token_file: PathBuf,
+}
+
+impl KubernetesClient {
+    pub fn new(settings: &KubernetesSettings) -> KubernetesClient {
+        let mut connector = Connector::new();
+        if Path::new(&settings.ca_file).exists() {
+            let files = TlsFiles { ca_files: vec![PathBuf::from(&settings.ca_file)], client_cert: None };
+            match client_config(&files) {
+                Ok(config) => connector = connector.rustls(Arc::new(config)),
+                Err(e) => error!("unable to trust {} for the kubernetes api, using the defaults: {e:#}", settings.ca_file),
+            }
+        }
+        KubernetesClient {
+            client: Client::builder()
+                .connector(connector)
+                .timeout(Duration::from_secs(settings.timeout_seconds))
+                .add_default_header((header::USER_AGENT, user_agent()))
+                .finish(),
+            api_server: settings.api_server.trim_end_matches('/').to_string(),
+            token_file: PathBuf::from(&settings.token_file),
+        }
+    }
+
+    pub fn from_settings() -> KubernetesClient {
+        KubernetesClient::new(&KubernetesSettings::from_settings())
+    }
+
+    /// pull_secrets collects the docker configs of a pod's imagePullSecrets, followed by those of its service
+    /// account.  Secrets that can't be read are logged and left out, so the lookup can still go ahead with
+    /// tolerable's own credentials.
+    pub async fn pull_secrets(&self, namespace: &str, spec: &Value) -> PullSecrets {
+        let mut names: Vec<String> = secret_names(spec.get("imagePullSecrets"));
+        let service_account = spec
+            .get("serviceAccountName")
+            .or_else(|| spec.get("serviceAccount"))
+            .and_then(|s| s.as_str())
+            .unwrap_or("default");
+        match self.get(&format!("/api/v1/namespaces/{namespace}/serviceaccounts/{service_account}")).await {
+            Ok(Some(account)) => {
+                for name in secret_names(account.get("imagePullSecrets")) {
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+            Ok(None) => debug!("service account {namespace}/{service_account} does not exist"),
+            Err(e) => warn!("unable to read service account {namespace}/{service_account}: {e:#}"),
+        }
+        let mut configs: Vec<(String, DockerConfig)> = Vec::new();
+        for name in names {
+            match self.get(&format!("/api/v1/namespaces/{namespace}/secrets/{name}")).await {
+                Ok(Some(secret)) => match secret_docker_config(&secret) {
+                    Ok(config) => configs.push((format!("{namespace}/{name}"), config)),
+                    Err(e) => warn!("ignoring pull secret {namespace}/{name}: {e:#}"),
+                },
+                Ok(None) => warn!("pull secret {namespace}/{name} does not exist"),
+                Err(e) => warn!("unable to read pull secret {namespace}/{name}: {e:#}"),
+            }
+        }
+        PullSecrets::new(configs)
+    }
+
+    /// get fetches an object from the api.  Ok(None) means it doesn't exist.
+    async fn get(&self, path: &str) -> anyhow::Result<Option<Value>> {
+        let url = format!("{}{}", self.api_server, path);
+        let mut request = self.client.get(&url).insert_header((header::ACCEPT, "application/json"));
+        if let Ok(token) = fs::read_to_string(&self.token_file) {
+            request = request.bearer_auth(token.trim());
+        }
+        let mut rs = request.send().await.map_err(|e| anyhow!("{url}: {e}"))?;
+        match rs.status().as_u16() {
+            404 => Ok(None),
+            s if (200..300).contains(&s) => Ok(Some(rs.json::<Value>().await.with_context(|| format!("{url} sent invalid json"))?)),
+            s => bail!("{url} answered HTTP {s}"),
+        }
+    }
+}
+
+/// secret_names are the names in a list of `{"name": ...}` references, such as `imagePullSecrets`.
+fn secret_names(references: Option<&Value>) -> Vec<String> {
+    references
+        .and_then(|r| r.as_array())
+        .map(|r| r.iter().filter_map(|s| s.get("name").and_then(|n| n.as_str())).map(|n| n.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// secret_docker_config reads the docker config out of a `kubernetes.io/dockerconfigjson` or
+/// `kubernetes.io/dockercfg` secret.
+pub fn secret_docker_config(secret: &Value) -> anyhow::Result<DockerConfig> {
+    let key = match secret.get("type").and_then(|t| t.as_str()) {
+        Some("kubernetes.io/dockerconfigjson") => ".dockerconfigjson",
+        Some("kubernetes.io/dockercfg") => ".dockercfg",
+        other => bail!("has type {}, not kubernetes.io/dockerconfigjson", other.unwrap_or("none")),
+    };
+    let data = secret
+        .get("data")
+        .and_then(|d| d.get(key))
+        .and_then(|d| d.as_str())
+        .ok_or_else(|| anyhow!("has no {key}"))?;
+    let text = general_purpose::STANDARD.decode(data).with_context(|| format!("{key} is not base64"))?;
+    DockerConfig::parse(&String::from_utf8_lossy(&text))
+}
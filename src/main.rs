@@ -1,9 +1,22 @@
+mod auth;
+mod cache;
 mod consts;
+mod credential_provider;
+mod credentials;
+mod errors;
+mod hosts;
+mod kubernetes;
 mod metrics;
 mod models;
 mod mutation;
 mod tests;
 mod manifest;
+mod platform;
+mod reference;
+mod registry;
+mod shortnames;
+mod singleflight;
+mod tls;
 
 #[macro_use]
 extern crate log;
@@ -15,8 +28,10 @@ extern crate prometheus;
 
 use actix_web::{middleware, web, App, HttpResponse, HttpServer};
 
+use crate::kubernetes::{KubernetesClient, KubernetesSettings};
 use crate::metrics::{register_metrics, APPVER, STATIC_PROM};
 use crate::mutation::mutate_handler;
+use crate::registry::RegistryClient;
 use config::{Config};
 use rustls::ServerConfig;
 use rustls_pemfile;
@@ -39,6 +54,7 @@ lazy_static! {
                 .try_parsing(true)
                 .list_separator(",")
                 .with_list_parse_key("supported_architectures")
+                .with_list_parse_key("docker_config_files")
             )
             .build()
             {
@@ -89,9 +105,18 @@ async fn main() -> anyhow::Result<()> {
         .with_no_client_auth()
         .with_single_cert(certs, privkey)
         .unwrap();
+    let pull_secrets = KubernetesSettings::from_settings().pull_secrets;
+    if pull_secrets {
+        info!("looking images up with the pull secrets of each pod");
+    }
     // fire up server and lets go!
     HttpServer::new(move || {
-        App::new()
+        // each worker gets its own registry client, and with it its own connection pools.
+        let mut app = App::new().app_data(web::Data::new(RegistryClient::from_settings()));
+        if pull_secrets {
+            app = app.app_data(web::Data::new(KubernetesClient::from_settings()));
+        }
+        app
             .wrap(middleware::Logger::default())
             .wrap(STATIC_PROM.clone())
             .service(mutate_handler)
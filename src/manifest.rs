@@ -1,266 +1,902 @@
 use std::fmt;
-use std::path::Path;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use time::OffsetDateTime;
 use anyhow::{bail};
-use awc::{Client, ClientRequest, SendClientRequest};
+use actix_web::http::header::HeaderMap;
+use awc::{ClientRequest, SendClientRequest};
 use awc::error::JsonPayloadError;
-use config::Config;
-use regex::Regex;
-use docker_image_reference::Reference;
-use crate::read_setting_string;
-use cached::proc_macro::cached;
 use serde_json::Value;
+use sha2::{Digest, Sha256, Sha512};
+use crate::auth::{auth_challenge, AuthChallenge, RegistryAuth, TokenResponse, TokenScope, TOKEN_CACHE};
+use crate::cache::PLATFORM_CACHE;
 use crate::consts::*;
+use crate::credentials::{get_credentials_for_registry, PullSecrets, RegistryCredential};
+use crate::errors::LookupError;
+use crate::metrics::{LOOKUPS_COALESCED, LOOKUP_ERRORS};
+use crate::platform::Platform;
+use crate::reference::ImageReference;
+use crate::registry::{concurrency_limit, AuthMode, Endpoint, RegistryClient, RegistrySettings, REGISTRIES};
+use crate::shortnames::SHORT_NAMES;
+use crate::singleflight::{wait_for_flight, Flight, IN_FLIGHT};
 
 
-lazy_static! {
-            static ref DOCKER_RE: Regex = Regex::new(DOCKER_IMAGE_REGEXP).unwrap();
-            static ref TOKEN_AUTH_RE: Regex = Regex::new(TOKEN_AUTH_REGEXP).unwrap();
+// how many levels of index-inside-an-index we are willing to follow before giving up.
+const MAX_INDEX_DEPTH: usize = 4;
+// the first retry of a transient failure waits this long, and every retry after that twice as long as the last.
+const RETRY_BACKOFF_MILLISECONDS: u64 = 250;
+
+/// ImagePlatforms is the answer to "where can this image run?"
+#[derive(Debug, PartialEq, Clone)]
+pub struct ImagePlatforms {
+    /// image is the reference that was looked up
+    pub image: String,
+    /// digest is the digest of the top-level manifest the registry served us
+    pub digest: String,
+    /// platforms are the platforms the image has been built for
+    pub platforms: Vec<Platform>,
 }
 
-#[cached]
-pub async fn validate_manifest(image: String) -> Option<Vec<String>> {
+/// FetchedManifest is a manifest body along with what the registry told us about it.
+pub struct FetchedManifest {
+    pub json: Value,
+    pub content_type: String,
+    pub digest: String,
+}
 
-    let mut manifest_ref: Reference = match Reference::from_str(&image){
-        Ok(m) => m,
+/// ManifestTarget is where an image's manifest lives and what we need to ask for it.
+pub struct ManifestTarget {
+    /// image is the canonical form of the reference, e.g. `docker.io/library/nginx:latest`
+    pub image: String,
+    /// registry is the registry name as the image spells it, which credentials are stored under
+    pub registry: String,
+    /// settings are the `[registries."<host>"]` settings that apply to the image
+    pub settings: RegistrySettings,
+    /// registryport is the host, with its port if it has one, of the registry itself
+    pub registryport: String,
+    /// endpoints are where to fetch the manifest from: mirrors first, then the registry itself
+    pub endpoints: Vec<Endpoint>,
+    /// image_name is the repository within the registry
+    pub image_name: String,
+    /// reference is the tag or digest of the manifest
+    pub reference: String,
+}
+
+impl ManifestTarget {
+    /// key is the normalized reference of the manifest, so different spellings of one image share lookups.
+    pub fn key(&self) -> String {
+        if self.reference.contains(':') {
+            format!("{}/{}@{}", self.registryport, self.image_name, self.reference)
+        } else {
+            format!("{}/{}:{}", self.registryport, self.image_name, self.reference)
+        }
+    }
+
+    /// candidate_endpoints are the endpoints that can answer for the reference.  Tags can only be resolved by
+    /// endpoints with the resolve capability, while any of them will do for a digest.
+    pub fn candidate_endpoints(&self) -> Vec<&Endpoint> {
+        let by_digest = self.reference.contains(':');
+        self.endpoints.iter().filter(|e| by_digest || e.resolve).collect()
+    }
+}
+
+/// validate_manifest finds out which platforms an image can run on, preferring the credentials in the pod's pull
+/// secrets.  Short names are tried on each registry they could come from, as registries.conf says, and the first
+/// that has the image wins.
+pub async fn validate_manifest(client: &RegistryClient, image: String, secrets: &PullSecrets) -> Result<ImagePlatforms, LookupError> {
+    let candidates = SHORT_NAMES.candidates(&image);
+    let mut errors: Vec<LookupError> = Vec::new();
+    for candidate in candidates {
+        match validate_qualified_manifest(client, candidate.clone(), secrets).await {
+            Ok(platforms) => return Ok(platforms),
+            Err(e) => {
+                debug!("{image} is not {candidate}: {e}");
+                errors.push(e);
+            }
+        }
+    }
+    // a registry that has the image but couldn't be asked says more than the ones that never heard of it.
+    let missing = |e: &LookupError| matches!(e, LookupError::NotFound { .. } | LookupError::Unauthorized { .. });
+    match errors.iter().position(|e| !missing(e)) {
+        Some(i) => Err(errors.swap_remove(i)),
+        None => Err(errors.pop().expect("there is always at least one candidate")),
+    }
+}
+
+async fn validate_qualified_manifest(client: &RegistryClient, image: String, secrets: &PullSecrets) -> Result<ImagePlatforms, LookupError> {
+    // what a pull secret could see is remembered apart from what everyone else can see.
+    let key = match normalize_image(&image).and_then(|t| Ok((secrets.credential_for(&t.registry, &t.image_name)?, t.key()))) {
+        Ok((Some(credential), key)) => format!("{key}#{}", credential.fingerprint()),
+        Ok((None, key)) => key,
         Err(e) => {
-            warn!{"unable to match manifest from image string: {}", e};
-            return None;
+            LOOKUP_ERRORS.with_label_values(&[e.kind()]).inc();
+            return Err(e);
         }
     };
-    // first attempt to hit the endpoint
-    let mut registryport = "".to_string();
-    // we will start with the docker hub registry as its the assumed default.
-    let mut registry = "docker.io";
-    let mut tag = "";
-    if manifest_ref.registry_name().is_some() {
-        registry = manifest_ref.registry_name().unwrap();
-    }
-    let cred = get_credentials_for_registry(registry.to_string()).await;
-    if registry == "docker.io" {
-        // see consts.rs for commentary
-        registry = ACTUAL_DOCKER_REGISTRY;
-    }
-    if manifest_ref.registry_port().is_some() {
-        registryport = format!("{}{}", registry, manifest_ref.registry_port().unwrap())
-    } else {
-        registryport = format!("{}", registry);
+    // a follower whose leader gave up goes round again, but is still only one coalesced lookup.
+    let mut coalesced = false;
+    loop {
+        if let Some(cached) = PLATFORM_CACHE.get(&key) {
+            return cached;
+        }
+        // an expired answer is still good enough to admit this pod with, as long as someone checks on it.
+        if let Some((stale, revalidate)) = PLATFORM_CACHE.get_stale(&key) {
+            if revalidate {
+                actix_web::rt::spawn(revalidate_image(
+                    client.clone(),
+                    image.clone(),
+                    key.clone(),
+                    stale.digest.clone(),
+                    secrets.clone(),
+                ));
+            }
+            return Ok(stale);
+        }
+        match IN_FLIGHT.join(&key) {
+            Flight::Leader(guard) => {
+                let result = lookup_image(client, &image, secrets).await;
+                if let Err(e) = &result {
+                    LOOKUP_ERRORS.with_label_values(&[e.kind()]).inc();
+                }
+                PLATFORM_CACHE.insert(&key, result.clone());
+                guard.complete(result.clone());
+                return result;
+            }
+            Flight::Follower(receiver) => {
+                if !coalesced {
+                    LOOKUPS_COALESCED.inc();
+                    coalesced = true;
+                }
+                if let Some(result) = wait_for_flight(receiver).await {
+                    return result;
+                }
+                debug!("lookup of {key} was abandoned, trying again");
+            }
+        }
     }
-    if manifest_ref.tag().is_some() {
-        tag = manifest_ref.tag().unwrap();
-    } else {
-        tag = "latest";
+}
+
+/// revalidate_image refreshes a stale cache entry.  A HEAD request tells us which manifest the tag points at now,
+/// and the full lookup only happens again if that has changed.
+async fn revalidate_image(client: RegistryClient, image: String, key: String, digest: String, secrets: PullSecrets) {
+    match head_manifest_digest(&client, &image, &secrets).await {
+        Ok(Some(current)) if current == digest => {
+            debug!("{image} is still at {digest}, keeping cached platforms");
+            PLATFORM_CACHE.refresh(&key);
+            return;
+        }
+        Ok(_) => {
+            debug!("{image} has moved from {digest}, looking it up again");
+        }
+        Err(e) => {
+            warn!("unable to revalidate {image}: {e}");
+            if e.is_transient() {
+                PLATFORM_CACHE.revalidation_failed(&key);
+                return;
+            }
+        }
+    }
+    let result = lookup_image(&client, &image, &secrets).await;
+    if let Err(e) = &result {
+        LOOKUP_ERRORS.with_label_values(&[e.kind()]).inc();
+        if e.is_transient() {
+            warn!("unable to look up {image} again, still serving cached platforms: {e}");
+            PLATFORM_CACHE.revalidation_failed(&key);
+            return;
+        }
+    }
+    PLATFORM_CACHE.insert(&key, result);
+}
+
+/// head_manifest_digest asks the registry which manifest an image currently resolves to, without downloading it.
+/// Ok(None) means the registry didn't say.
+pub async fn head_manifest_digest(client: &RegistryClient, image: &str, secrets: &PullSecrets) -> Result<Option<String>, LookupError> {
+    let target = normalize_image(image)?;
+    if target.reference.contains(':') {
+        // pinned by digest, so it can't have moved.
+        return Ok(Some(target.reference));
+    }
+    let endpoints = target.candidate_endpoints();
+    let mut last_error = None;
+    for (i, endpoint) in endpoints.iter().enumerate() {
+        match head_endpoint_digest(client, &target, endpoint, secrets).await {
+            Ok(digest) => return Ok(digest),
+            Err(e) => {
+                if i + 1 < endpoints.len() {
+                    warn!("unable to check {image} on {}, trying the next endpoint: {e}", endpoint.api_root);
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.expect("the registry itself is always an endpoint"))
+}
+
+async fn head_endpoint_digest(client: &RegistryClient, target: &ManifestTarget, endpoint: &Endpoint, secrets: &PullSecrets) -> Result<Option<String>, LookupError> {
+    let credentials = endpoint_credentials(target, endpoint, secrets).await?;
+    let auth = get_registry_auth(client, endpoint, &target.image_name, credentials.clone()).await?;
+    match head_digest(client, target, endpoint, auth.clone()).await {
+        Err(Rejected { error, challenge: Some(header) }) => {
+            match rechallenge(client, endpoint, &target.image_name, &header, credentials, &auth).await? {
+                Some(retry) => head_digest(client, target, endpoint, Some(retry)).await.map_err(|r| r.error),
+                None => Err(error),
+            }
+        }
+        result => result.map_err(|r| r.error),
+    }
+}
+
+async fn head_digest(client: &RegistryClient, target: &ManifestTarget, endpoint: &Endpoint, auth: Option<RegistryAuth>) -> Result<Option<String>, Rejected> {
+    let url = format!("{}/{}/manifests/{}", endpoint.api_root, target.image_name, target.reference);
+    let mut head_req: ClientRequest = client.head(&url).insert_header(("Accept", manifest_media_types().join(",")));
+    if let Some(a) = &auth {
+        head_req = a.apply(head_req);
+    }
+    debug!("MANIFEST HEAD REQ: HEAD {}", url);
+    let head_rs = match head_req.send().await {
+        Ok(r) => r,
+        Err(e) => {
+            return Err(LookupError::Unreachable { registry: endpoint.host.clone(), message: e.to_string() }.into());
+        }
+    };
+    if !head_rs.status().is_success() {
+        return Err(rejected(endpoint, &target.image_name, head_rs.status().as_u16(), head_rs.headers()));
+    }
+    Ok(head_rs
+        .headers()
+        .get("docker-content-digest")
+        .and_then(|d| d.to_str().ok())
+        .map(|d| d.to_string()))
+}
+
+/// lookup_image runs lookup_image_platforms within the registry's concurrency limit, trying again after transient
+/// failures for as long as the registry's retry budget lasts.
+async fn lookup_image(client: &RegistryClient, image: &str, secrets: &PullSecrets) -> Result<ImagePlatforms, LookupError> {
+    let target = normalize_image(image)?;
+    let _permit = match target.settings.concurrency {
+        Some(limit) => concurrency_limit(&target.registryport, limit).acquire_owned().await.ok(),
+        None => None,
+    };
+    let mut attempt: u32 = 0;
+    loop {
+        let result = lookup_image_platforms(client, image, secrets).await;
+        match &result {
+            Err(e) if e.is_transient() && attempt < target.settings.retries => {
+                attempt += 1;
+                let backoff = Duration::from_millis(RETRY_BACKOFF_MILLISECONDS << (attempt - 1).min(6));
+                warn!("lookup of {image} failed, trying again in {:?} ({attempt}/{}): {e}", backoff, target.settings.retries);
+                actix_web::rt::time::sleep(backoff).await;
+            }
+            _ => return result,
+        }
+    }
+}
+
+/// lookup_image_platforms tries the image's endpoints in order, falling back to the next one whenever an endpoint
+/// can't give an answer, as containerd does when pulling.
+async fn lookup_image_platforms(client: &RegistryClient, image: &str, secrets: &PullSecrets) -> Result<ImagePlatforms, LookupError> {
+    let target = normalize_image(image)?;
+    let endpoints = target.candidate_endpoints();
+    let mut last_error = None;
+    for (i, endpoint) in endpoints.iter().enumerate() {
+        match lookup_endpoint_platforms(client, &target, endpoint, secrets).await {
+            Ok(platforms) => return Ok(platforms),
+            Err(e) => {
+                if i + 1 < endpoints.len() {
+                    warn!("unable to look up {image} on {}, trying the next endpoint: {e}", endpoint.api_root);
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.expect("the registry itself is always an endpoint"))
+}
+
+async fn lookup_endpoint_platforms(client: &RegistryClient, target: &ManifestTarget, endpoint: &Endpoint, secrets: &PullSecrets) -> Result<ImagePlatforms, LookupError> {
+    let credentials = endpoint_credentials(target, endpoint, secrets).await?;
+    let mut auth = get_registry_auth(client, endpoint, &target.image_name, credentials.clone()).await?;
+    let manifest = match try_fetch_manifest(client, endpoint, &target.image_name, &target.reference, auth.clone()).await {
+        Ok(m) => m,
+        Err(Rejected { error, challenge: Some(header) }) => {
+            match rechallenge(client, endpoint, &target.image_name, &header, credentials, &auth).await? {
+                Some(retry) => {
+                    auth = Some(retry);
+                    fetch_manifest(client, endpoint, &target.image_name, &target.reference, auth.clone()).await?
+                }
+                None => return Err(error),
+            }
+        }
+        Err(rejected) => return Err(rejected.error),
+    };
+    let digest = manifest.digest.clone();
+    let platforms = resolve_manifest(client, endpoint, &target.image_name, manifest, auth, 0).await?;
+    Ok(ImagePlatforms { image: target.image.clone(), digest, platforms })
+}
+
+/// endpoint_credentials picks the credentials for one of an image's endpoints.  Mirrors use credentials stored
+/// under their own host, and the registry itself the pod's pull secrets, or else those of the registry name the
+/// image uses.
+async fn endpoint_credentials(target: &ManifestTarget, endpoint: &Endpoint, secrets: &PullSecrets) -> Result<Option<RegistryCredential>, LookupError> {
+    // the kubelet hands pull secrets to the registry only, so mirrors don't get them either.
+    if !endpoint.mirror {
+        if let Some(cred) = secrets.credential_for(&target.registry, &target.image_name)? {
+            return Ok(Some(cred));
+        }
+    }
+    match endpoint.mirror {
+        true => get_credentials_for_registry(endpoint.host.clone(), target.image_name.clone(), None).await,
+        false => {
+            let credential_file = target.settings.credential_file.clone();
+            get_credentials_for_registry(target.registry.clone(), target.image_name.clone(), credential_file).await
+        }
+    }
+}
+
+/// normalize_image parses an image into the manifest it refers to, filling in docker hub's defaults and applying
+/// the settings of the registry it comes from.
+pub fn normalize_image(image: &str) -> Result<ManifestTarget, LookupError> {
+
+    let parsed = match ImageReference::parse(image) {
+        Ok(r) => r,
+        Err(e) => {
+            return Err(LookupError::InvalidReference { image: image.to_string(), message: e.to_string() });
+        }
+    };
+    let (registry_key, settings) = REGISTRIES.resolve(&parsed.domain);
+    if !settings.enabled {
+        return Err(LookupError::RegistryDisabled { registry: registry_key });
     }
+    let registryport = settings.endpoint_host().unwrap_or_else(|| registry_key.clone());
 
-    // Docker legacy image names can be specified without a repository name ('nginx' vs 'library/nginx') so
-    // we will fix these here, as the backend for docker.io at least requires that in the api call.
-    let mut image_name: String;
-    if !manifest_ref.name().contains('/') {
-        image_name = format!("library/{}", manifest_ref.name());
+    // parsing already gave docker.io names their library/ prefix, but registries known by other names may want it
+    // too, e.g. an alias of docker hub.
+    let library_prefix = settings.library_prefix.unwrap_or(registry_key == ACTUAL_DOCKER_REGISTRY);
+    let image_name = if library_prefix && !parsed.path.contains('/') {
+        format!("library/{}", parsed.path)
     } else {
-        image_name = manifest_ref.name().to_owned();
+        parsed.path.clone()
+    };
+
+    // a digest we can't check the content against pins nothing, so only the algorithms we can verify will do.
+    if let Some(digest) = &parsed.digest {
+        if !matches!(digest.split_once(':'), Some(("sha256" | "sha512", _))) {
+            return Err(LookupError::InvalidReference {
+                image: image.to_string(),
+                message: format!("digest {digest} uses an unsupported algorithm"),
+            });
+        }
     }
+    // images pinned by digest are looked up by that digest alone, even when a tag is also present.
+    let reference = parsed.reference().to_string();
+    let endpoints = REGISTRIES.endpoints(&registry_key, &settings);
+    Ok(ManifestTarget {
+        image: parsed.clone().with_default_tag().to_string(),
+        registry: parsed.domain.clone(),
+        settings,
+        registryport,
+        endpoints,
+        image_name,
+        reference,
+    })
+}
+
+/// manifest_media_types is the Accept list for manifest requests: indexes first, then single-platform manifests.
+fn manifest_media_types() -> Vec<&'static str> {
+    vec![
+        OCI_IMAGE_INDEX_MEDIA_TYPE,
+        DOCKER_MANIFEST_LIST_MEDIA_TYPE,
+        OCI_IMAGE_MANIFEST_MEDIA_TYPE,
+        DOCKER_MANIFEST_MEDIA_TYPE,
+    ]
+}
 
-    let url = format!("https://{registryport}/v2/{}/manifests/{tag}", image_name);
-    let token = get_jwt(url.clone(), cred).await;
-    let client = Client::new();
-    let image_manifest_types = vec![
-        "application/vnd.oci.image.index.v1+json",
-        "application/vnd.docker.distribution.manifest.list.v2+json"
-    ];
-    let mut manifest_req :ClientRequest= client.get(&url).insert_header(("Accept", image_manifest_types.join(",")));
-    if token.is_some() {
-        manifest_req = manifest_req.bearer_auth(token.unwrap());
+/// resolve_manifest works out which platforms a manifest covers, fetching and walking into nested indexes.  It is
+/// boxed because it recurses.
+fn resolve_manifest<'a>(
+    client: &'a RegistryClient,
+    endpoint: &'a Endpoint,
+    image_name: &'a str,
+    manifest: FetchedManifest,
+    auth: Option<RegistryAuth>,
+    depth: usize,
+) -> Pin<Box<dyn Future<Output = Result<Vec<Platform>, LookupError>> + 'a>> {
+    Box::pin(async move {
+        let rs_json = manifest.json;
+        let invalid = |message: &str| LookupError::InvalidResponse {
+            registry: endpoint.host.clone(),
+            repository: image_name.to_string(),
+            message: message.to_string(),
+        };
+
+        // depending on schemaVersion, we need to work a little differently.
+        let schemaVersion: u64 = match rs_json.get("schemaVersion").and_then(|v| v.as_u64()) {
+            Some(v) => v,
+            None => {
+                return Err(invalid("manifest has no schemaVersion, so we can't deduce where a platform value would live"));
+            }
+        };
+        match schemaVersion {
+            1 => get_version_1_platforms(&rs_json).ok_or_else(|| invalid("schema 1 manifest has no architecture")),
+            2 => {
+                if is_image_manifest(&rs_json, &manifest.content_type) {
+                    // a single-platform image; the platform is only recorded in the image config blob.
+                    let config = fetch_image_config(client, endpoint, image_name, &rs_json, auth).await?;
+                    return get_config_platforms(&config).ok_or_else(|| invalid("image config has no architecture"));
+                }
+                let entries = match get_version_2_entries(&rs_json) {
+                    Some(e) => e,
+                    None => {
+                        return Err(LookupError::UnsupportedMediaType {
+                            registry: endpoint.host.clone(),
+                            repository: image_name.to_string(),
+                            media_type: manifest.content_type.clone(),
+                        });
+                    }
+                };
+                let mut platforms: Vec<Platform> = Vec::new();
+                for entry in entries {
+                    let found = match entry {
+                        IndexEntry::Platform(p) => vec![p],
+                        IndexEntry::Nested(digest) => {
+                            if depth >= MAX_INDEX_DEPTH {
+                                warn!("not following {digest} in {image_name}, indexes are nested too deeply");
+                                continue;
+                            }
+                            let nested = match fetch_manifest(client, endpoint, image_name, &digest, auth.clone()).await {
+                                Ok(m) => resolve_manifest(client, endpoint, image_name, m, auth.clone(), depth + 1).await,
+                                Err(e) => Err(e),
+                            };
+                            match nested {
+                                Ok(p) => p,
+                                Err(e) => {
+                                    // one bad entry shouldn't cost us the rest of the index.
+                                    warn!("skipping index entry {digest} in {image_name}: {e}");
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+                    for p in found {
+                        if !platforms.contains(&p) {
+                            platforms.push(p);
+                        }
+                    }
+                }
+                debug!("v2 platforms discovered {:#?}", platforms);
+                if platforms.is_empty() {
+                    return Err(LookupError::NoPlatforms {
+                        registry: endpoint.host.clone(),
+                        repository: image_name.to_string(),
+                    });
+                }
+                Ok(platforms)
+            }
+            _ => Err(invalid(&format!("unexpected schemaVersion {}", schemaVersion))),
+        }
+    })
+}
+
+/// fetch_manifest downloads a manifest by tag or digest.
+pub async fn fetch_manifest(client: &RegistryClient, endpoint: &Endpoint, image_name: &str, reference: &str, auth: Option<RegistryAuth>) -> Result<FetchedManifest, LookupError> {
+    try_fetch_manifest(client, endpoint, image_name, reference, auth).await.map_err(|r| r.error)
+}
+
+/// try_fetch_manifest is fetch_manifest, keeping the challenge of a 401 so the caller can answer it.
+async fn try_fetch_manifest(client: &RegistryClient, endpoint: &Endpoint, image_name: &str, reference: &str, auth: Option<RegistryAuth>) -> Result<FetchedManifest, Rejected> {
+    let url = format!("{}/{image_name}/manifests/{reference}", endpoint.api_root);
+    let mut manifest_req :ClientRequest= client.get(&url).insert_header(("Accept", manifest_media_types().join(",")));
+    if let Some(a) = &auth {
+        manifest_req = a.apply(manifest_req);
     }
-    debug!("MANIFEST REQ: {:#?}", manifest_req);
+    debug!("MANIFEST REQ: GET {}", url);
     let mut manifest_rs = match manifest_req.send().await {
         Ok(r) => r,
         Err(e) => {
-            warn!("Unable to contact for manifest request: {e}");
-            return None;
+            return Err(LookupError::Unreachable { registry: endpoint.host.clone(), message: e.to_string() }.into());
         }
     };
-        
+    if !manifest_rs.status().is_success() {
+        return Err(rejected(endpoint, image_name, manifest_rs.status().as_u16(), manifest_rs.headers()));
+    }
+    let content_type = match manifest_rs.headers().get("content-type") {
+        Some(ct) => ct.to_str().unwrap_or("").to_string(),
+        None => "".to_string(),
+    };
 
     // due to the fact that docker.io returns a mime type that actix-web doesn't like, we'll save the body to
     // a variable and attempt to convert it to json there.
     let rs_body = match manifest_rs.body().await {
         Ok(b) => b,
         Err(e) => {
-            warn!("Unable to decode payload of manifest response: {e}");
-            return None;
+            return Err(LookupError::Unreachable { registry: endpoint.host.clone(), message: e.to_string() }.into());
         }
     };
+    // tags can't contain a colon, so anything that does is a digest we can hold the registry to.
+    if reference.contains(':') && !verify_digest(reference, rs_body.as_ref()) {
+        return Err(LookupError::DigestMismatch {
+            registry: endpoint.host.clone(),
+            repository: image_name.to_string(),
+            digest: reference.to_string(),
+        }
+        .into());
+    }
+    let digest = format!("sha256:{:x}", Sha256::digest(rs_body.as_ref()));
 
-    let rs_json: Value = match serde_json::from_slice(rs_body.as_ref()) {
-        Ok(m) => m,
+    match serde_json::from_slice(rs_body.as_ref()) {
+        Ok(json) => Ok(FetchedManifest { json, content_type, digest }),
         Err(e) => {
-            warn!("Error decoding result from manifest request: {e}");
             debug!("{:#?}", manifest_rs);
-            debug!("{:#?}", manifest_rs.body().await);
-            return None;
+            Err(LookupError::InvalidResponse {
+                registry: endpoint.host.clone(),
+                repository: image_name.to_string(),
+                message: format!("manifest is not valid json: {e}"),
+            }
+            .into())
         }
+    }
+}
+
+/// Rejected is a failed registry request.  A 401 keeps the `www-authenticate` header it came with, as the registry
+/// may want something for the repository other than what its /v2/ asked for.
+struct Rejected {
+    error: LookupError,
+    challenge: Option<String>,
+}
+
+impl From<LookupError> for Rejected {
+    fn from(error: LookupError) -> Self {
+        Rejected { error, challenge: None }
+    }
+}
+
+fn rejected(endpoint: &Endpoint, image_name: &str, status: u16, headers: &HeaderMap) -> Rejected {
+    let challenge = match status {
+        401 => headers.get("www-authenticate").and_then(|h| h.to_str().ok()).map(|h| h.to_string()),
+        _ => None,
     };
+    Rejected { error: LookupError::from_status(&endpoint.host, image_name, status), challenge }
+}
+
+/// is_image_manifest decides whether a schema 2 response is a single-platform image manifest rather than an index.
+/// OCI manifests are allowed to leave out mediaType, so we fall back to the content-type and then to the shape.
+pub fn is_image_manifest(json: &Value, content_type: &str) -> bool {
+    let media_type = match json.get("mediaType").and_then(|m| m.as_str()) {
+        Some(m) => m.to_string(),
+        None => content_type.split(';').next().unwrap_or("").trim().to_string(),
+    };
+    match media_type.as_str() {
+        OCI_IMAGE_MANIFEST_MEDIA_TYPE | DOCKER_MANIFEST_MEDIA_TYPE => true,
+        OCI_IMAGE_INDEX_MEDIA_TYPE | DOCKER_MANIFEST_LIST_MEDIA_TYPE => false,
+        _ => json.get("manifests").is_none() && json.get("config").is_some(),
+    }
+}
 
-    // depending on schemaVersion, we need to work a little differently.
-    let jsondata: String = format!("{:#?}",rs_json);
-    let schemaVersion: u64 = match rs_json.get("schemaVersion") {
-        Some(v) => v.as_u64().unwrap(),
+/// fetch_image_config follows the config descriptor of an image manifest and downloads the config blob.
+pub async fn fetch_image_config(client: &RegistryClient, endpoint: &Endpoint, image_name: &str, manifest: &Value, auth: Option<RegistryAuth>) -> Result<Value, LookupError> {
+    let invalid = |message: String| LookupError::InvalidResponse {
+        registry: endpoint.host.clone(),
+        repository: image_name.to_string(),
+        message,
+    };
+    let digest = match manifest.get("config").and_then(|c| c.get("digest")).and_then(|d| d.as_str()) {
+        Some(d) => d,
         None => {
-            warn!("Response had no schemaVersion, so we can't deduce where a platform value would live.");
-            return None;
+            return Err(invalid("image manifest has no config descriptor".to_string()));
         }
     };
-    return match schemaVersion {
-        1 => get_version_1_arches(&rs_json),
-        2 => get_version_2_arches(&rs_json),
-        _ => {
-            warn!("We received a value we did not expect for schemaVersion: {}", schemaVersion);
+    let url = format!("{}/{image_name}/blobs/{digest}", endpoint.api_root);
+    let mut config_req: ClientRequest = client.get(&url);
+    if let Some(a) = &auth {
+        config_req = a.apply(config_req);
+    }
+    debug!("CONFIG REQ: GET {}", url);
+    let mut config_rs = match config_req.send().await {
+        Ok(r) => r,
+        Err(e) => {
+            return Err(LookupError::Unreachable { registry: endpoint.host.clone(), message: e.to_string() });
+        }
+    };
+    if !config_rs.status().is_success() {
+        return Err(LookupError::from_status(&endpoint.host, image_name, config_rs.status().as_u16()));
+    }
+    let body = match config_rs.body().await {
+        Ok(b) => b,
+        Err(e) => {
+            return Err(LookupError::Unreachable { registry: endpoint.host.clone(), message: e.to_string() });
+        }
+    };
+    if !verify_digest(digest, body.as_ref()) {
+        return Err(LookupError::DigestMismatch {
+            registry: endpoint.host.clone(),
+            repository: image_name.to_string(),
+            digest: digest.to_string(),
+        });
+    }
+    serde_json::from_slice(body.as_ref()).map_err(|e| invalid(format!("config blob {digest} is not valid json: {e}")))
+}
+
+/// verify_digest checks content against a registry digest string such as `sha256:<hex>`.
+pub fn verify_digest(digest: &str, content: &[u8]) -> bool {
+    match digest.split_once(':') {
+        Some(("sha256", hex)) => format!("{:x}", Sha256::digest(content)) == hex.to_lowercase(),
+        Some(("sha512", hex)) => format!("{:x}", Sha512::digest(content)) == hex.to_lowercase(),
+        Some((algorithm, _)) => {
+            warn!("unable to verify digest with unsupported algorithm {algorithm}");
+            false
+        }
+        None => {
+            warn!("malformed digest {digest}");
+            false
+        }
+    }
+}
+
+pub fn get_config_platforms(json: &Value) -> Option<Vec<Platform>> {
+    match Platform::from_json(json) {
+        Some(platform) => {
+            debug!("image config platform discovered: {platform}");
+            Some(vec![platform])
+        }
+        None => {
+            warn!("image config exists but no architecture found");
             None
         }
     }
 }
 
-pub fn get_version_1_arches(json: &Value) -> Option<Vec<String>> {
-    let mut arches:Vec<String> = vec![];
-    match json.get("architecture") {
-        Some(s) => arches.push(s.as_str().unwrap().to_string()),
+pub fn get_version_1_platforms(json: &Value) -> Option<Vec<Platform>> {
+    // schema 1 manifests predate multi-os images, so they only ever describe linux.
+    match json.get("architecture").and_then(|a| a.as_str()) {
+        Some(arch) => Some(vec![Platform::new("linux", arch, None)]),
         None => {
             warn!("Schema version 1, but no architecture specified");
-            return None
+            None
         }
-    };
-    Some(arches)
+    }
+}
+
+/// IndexEntry is what we make of one descriptor in an image index.
+#[derive(Debug, PartialEq, Clone)]
+pub enum IndexEntry {
+    /// an image manifest that declares the platform it was built for
+    Platform(Platform),
+    /// a manifest or index without a platform, which has to be fetched by digest to find out
+    Nested(String),
 }
 
-pub fn get_version_2_arches(json: &Value) -> Option<Vec<String>> {
-    let manifests = match json.get("manifests") {
-        Some(m) => m.as_array().unwrap(),
+/// get_version_2_entries sorts the descriptors of an image index into platforms we can use right away and
+/// manifests we still need to fetch.  Attestations, artifacts and other non-image entries are dropped.
+pub fn get_version_2_entries(json: &Value) -> Option<Vec<IndexEntry>> {
+    let manifests = match json.get("manifests").and_then(|m| m.as_array()) {
+        Some(m) => m,
         None => {
             warn!("no manifests found: {:#?}", json);
             return None;
         }
     };
-    let mut arches = Vec::new();
+    let mut entries = Vec::new();
     for mani in manifests {
+        let digest = mani.get("digest").and_then(|d| d.as_str()).unwrap_or("");
+        // buildkit marks its provenance/sbom manifests with this annotation (and an unknown/unknown platform).
+        if mani.pointer("/annotations/vnd.docker.reference.type").is_some() {
+            debug!("skipping attestation manifest {digest}");
+            continue;
+        }
+        if mani.get("artifactType").is_some() {
+            debug!("skipping artifact manifest {digest}");
+            continue;
+        }
+        let media_type = mani.get("mediaType").and_then(|m| m.as_str()).unwrap_or("");
+        let nested_index = matches!(media_type, OCI_IMAGE_INDEX_MEDIA_TYPE | DOCKER_MANIFEST_LIST_MEDIA_TYPE);
+        let image_manifest = matches!(media_type, OCI_IMAGE_MANIFEST_MEDIA_TYPE | DOCKER_MANIFEST_MEDIA_TYPE);
+        if !media_type.is_empty() && !nested_index && !image_manifest {
+            debug!("skipping index entry {digest} with media type {media_type}");
+            continue;
+        }
         let platform = match mani.get("platform") {
             Some(p) => p,
             None => {
-                warn!("no platforms detected: {:#?}", mani);
-                return None;
+                if digest.is_empty() {
+                    warn!("index entry has neither a platform nor a digest: {:#?}", mani);
+                } else {
+                    entries.push(IndexEntry::Nested(digest.to_string()));
+                }
+                continue;
             }
         };
-        let arch = match platform.get("architecture") {
-            Some(a) => a.as_str().unwrap(),
+        if nested_index {
+            // a platform on an index entry only narrows things down; the nested index has the real list.
+            entries.push(IndexEntry::Nested(digest.to_string()));
+            continue;
+        }
+        let platform = match Platform::from_json(platform) {
+            Some(p) => p,
             None => {
                 warn!("platform exists but no architecture found: {:#?}", platform);
-                return None;
+                continue;
             }
         };
-        if !arches.contains(&arch.to_string()) {
-            arches.push(arch.to_string());
+        if platform.os == "unknown" || platform.architecture == "unknown" {
+            debug!("skipping index entry {digest} with unknown platform");
+            continue;
         }
+        entries.push(IndexEntry::Platform(platform));
     }
-    debug!("v2 architectures discovered {:#?}", arches);
-    Some(arches)
+    Some(entries)
 }
 
 
-pub async fn get_jwt(url: String, credentials: Option<RegistryCredential>) -> Option<String> {
-    let client = Client::new();
-
-    let mut rs = match client.get(&url).send().await {
-        Ok(r) => r,
-        Err(e) => {
-            warn!("Error on initial request for token data");
-            return None;
+/// get_registry_auth works out how to authenticate pulls of `image_name`.  What the registry requires is discovered
+/// once per registry by pinging its /v2/ endpoint, and bearer tokens come from TOKEN_CACHE while they are still good.
+/// Ok(None) means the registry doesn't want anything.
+pub async fn get_registry_auth(client: &RegistryClient, endpoint: &Endpoint, image_name: &str, credentials: Option<RegistryCredential>) -> Result<Option<RegistryAuth>, LookupError> {
+    let registry = endpoint.host.as_str();
+    match REGISTRIES.for_endpoint(registry).auth {
+        AuthMode::Auto => {}
+        AuthMode::Anonymous => return Ok(None),
+        AuthMode::Basic => {
+            let auth = credentials.as_ref().and_then(|c| c.basic_auth());
+            if auth.is_none() {
+                warn!("registry {registry} is set to basic auth, but we have no user and password for it");
+            }
+            return Ok(auth);
         }
+    }
+    // a registry token is meant for the registry itself, so there is nothing to ask for.
+    if let Some(RegistryCredential::RegistryToken(token)) = &credentials {
+        return Ok(Some(RegistryAuth::Bearer(token.clone())));
+    }
+    let challenge = match TOKEN_CACHE.challenge(registry) {
+        Some(c) => c,
+        None => match discover_auth(client, endpoint).await? {
+            Some(c) => {
+                TOKEN_CACHE.remember_challenge(registry, c.clone());
+                c
+            }
+            None => return Ok(None),
+        },
     };
+    challenge_auth(client, endpoint, image_name, challenge, None, credentials).await
+}
 
-    let auth = match rs.headers().get("www-authenticate") {
-        Some(val) => val.to_str().unwrap(),
+/// rechallenge answers the challenge a registry sent back for a request, for when a repository wants something
+/// other than its registry's /v2/ said, e.g. a private repository on an otherwise open registry.  The challenge is
+/// remembered for the registry, and a token is asked for with the scope it names.  Ok(None) means the challenge
+/// gives us nothing new to try.
+async fn rechallenge(client: &RegistryClient, endpoint: &Endpoint, image_name: &str, header: &str, credentials: Option<RegistryCredential>, tried: &Option<RegistryAuth>) -> Result<Option<RegistryAuth>, LookupError> {
+    let registry = endpoint.host.as_str();
+    if REGISTRIES.for_endpoint(registry).auth != AuthMode::Auto
+        || matches!(credentials, Some(RegistryCredential::RegistryToken(_)))
+    {
+        return Ok(None);
+    }
+    let (challenge, scope) = match auth_challenge(header) {
+        Some(c) => c,
         None => {
-            info!("we tried to query for a jwt but did not get www-authenticate header.");
-            return None;
+            warn!("registry {registry} sent a challenge we don't understand: {header}");
+            return Ok(None);
         }
     };
-    let cap = TOKEN_AUTH_RE.captures(auth).unwrap();
-    let authurl = format!("{}?service={}&scope={}",
-                          cap.name("url").unwrap().as_str(),
-                          cap.name("service").unwrap().as_str(),
-                          cap.name("scope").unwrap().as_str());
-    let mut auth_req = client.get(authurl.clone());
-    if credentials.is_some() {
-        auth_req = auth_req.basic_auth(credentials.clone().unwrap().user, credentials.clone().unwrap().secret);
-    }
-    debug!("AUTH REQ: {:#?}", auth_req);
-    let mut auth_rs = match auth_req
-        .send()
-        .await {
-        Ok(a) => a,
-        Err(e) => {
-            warn!("Couldn't get token from {}: {}", authurl, e);
-            return None;
+    debug!("registry {registry} wants {:?} for {image_name}", challenge);
+    TOKEN_CACHE.remember_challenge(registry, challenge.clone());
+    let auth = challenge_auth(client, endpoint, image_name, challenge, scope, credentials).await?;
+    Ok(auth.filter(|a| Some(a) != tried.as_ref()))
+}
+
+/// challenge_auth is what a challenge asks for: the credentials themselves for basic auth, or a token for `scope`,
+/// which is pulling the repository the endpoint serves unless the registry named one.
+async fn challenge_auth(client: &RegistryClient, endpoint: &Endpoint, image_name: &str, challenge: AuthChallenge, scope: Option<String>, credentials: Option<RegistryCredential>) -> Result<Option<RegistryAuth>, LookupError> {
+    let registry = endpoint.host.as_str();
+    match challenge {
+        AuthChallenge::Anonymous => Ok(None),
+        AuthChallenge::Basic => match credentials.as_ref().and_then(|c| c.basic_auth()) {
+            Some(auth) => Ok(Some(auth)),
+            None => {
+                warn!("registry {registry} wants basic auth, but we have no user and password for it");
+                Ok(None)
+            }
+        },
+        AuthChallenge::Bearer(bearer) => {
+            let mut token_scope = bearer.scope_for(&endpoint.repository(image_name));
+            if let Some(scope) = scope {
+                token_scope.scope = scope;
+            }
+            token_scope.credential = credentials.as_ref().map(|c| c.fingerprint()).unwrap_or_default();
+            if let Some(token) = TOKEN_CACHE.get(&token_scope) {
+                debug!("using cached token for {}", token_scope.scope);
+                return Ok(Some(RegistryAuth::Bearer(token)));
+            }
+            let token = fetch_token(client, registry, token_scope, credentials).await?;
+            Ok(Some(RegistryAuth::Bearer(token)))
         }
-    };
+    }
+}
 
-    let body = match auth_rs.json::<serde_json::Value>().await {
-        Ok(a) => a,
+/// discover_auth pings the /v2/ of an endpoint's host to find out what auth it requires.  Ok(None) means the registry gave no
+/// usable answer this time, so nothing should be remembered.
+async fn discover_auth(client: &RegistryClient, endpoint: &Endpoint) -> Result<Option<AuthChallenge>, LookupError> {
+    let registry = endpoint.host.as_str();
+    let rs = match client.get(&endpoint.ping_url()).send().await {
+        Ok(r) => r,
         Err(e) => {
-            warn!("Didn't deserialize body to json in response: {}", e);
-            return None
+            return Err(LookupError::Unreachable { registry: registry.to_string(), message: e.to_string() });
         }
     };
-    match body.get("token") {
-        Some(a) => Some(a.as_str().unwrap().parse().unwrap()),
+    if rs.status().is_success() {
+        debug!("registry {registry} needs no auth");
+        return Ok(Some(AuthChallenge::Anonymous));
+    }
+    if rs.status().as_u16() != 401 {
+        warn!("registry {registry} answered HTTP {} to /v2/, not remembering its auth", rs.status().as_u16());
+        return Ok(None);
+    }
+    let auth = match rs.headers().get("www-authenticate") {
+        Some(val) => val.to_str().unwrap_or("").to_string(),
         None => {
-            warn!("Couldn't find token in response.");
-            None
+            warn!("registry {registry} wants auth but did not send a www-authenticate header");
+            return Ok(None);
+        }
+    };
+    match auth_challenge(&auth) {
+        Some((c, _)) => {
+            debug!("registry {registry} wants {:?}", c);
+            Ok(Some(c))
+        }
+        None => {
+            warn!("registry {registry} sent a challenge we don't understand: {auth}");
+            Ok(None)
         }
     }
 }
 
-pub async fn get_credentials_for_registry(registry: String) -> Option<RegistryCredential> {
-    let cred_path = match read_setting_string("registry_credential_path") {
-        Ok(path) => path,
-        Err(e) => {
-            warn!{"cred path not found in config (registry_credential_path)"}
-            return None;
+/// fetch_token asks a token service for a token and remembers it for as long as the token service says it is good.
+/// A user and password go along as basic auth, and an identity token is traded in with an oauth2 refresh token
+/// grant, as docker does it.
+async fn fetch_token(client: &RegistryClient, registry: &str, scope: TokenScope, credentials: Option<RegistryCredential>) -> Result<String, LookupError> {
+    let auth_error = |message: String| LookupError::Auth { registry: registry.to_string(), message };
+    let authurl = scope.url();
+    let auth_send = match &credentials {
+        Some(RegistryCredential::IdentityToken(token)) => {
+            let form = [
+                ("grant_type", "refresh_token"),
+                ("service", scope.service.as_str()),
+                ("scope", scope.scope.as_str()),
+                ("client_id", APP_NAME),
+                ("refresh_token", token.as_str()),
+            ];
+            debug!("AUTH REQ: POST {}", scope.realm);
+            client.post(&scope.realm).send_form(&form)
+        }
+        _ => {
+            let mut auth_req = client.get(&authurl);
+            if let Some(RegistryCredential::Basic { user, secret }) = &credentials {
+                auth_req = auth_req.basic_auth(user, secret);
+            }
+            debug!("AUTH REQ: GET {}", authurl);
+            auth_req.send()
         }
     };
-    let cred_file = format!("{}/{}.toml",cred_path,registry);
-    if !Path::new(&cred_file).exists() {
-        warn!("credential file does not exist: {}", cred_file);
-        return None;
-    };
-    let cred: config::Config = match Config::builder()
-        .add_source(config::File::with_name(&cred_file))
-        .build(){
-        Ok(config) => config,
+    let mut auth_rs = match auth_send.await {
+        Ok(a) => a,
         Err(e) => {
-            warn!("path '{}' exists but config couldn't initialize", cred_file);
-            return None;
+            return Err(LookupError::Unreachable { registry: registry.to_string(), message: format!("token service {authurl}: {e}") });
         }
     };
+    if !auth_rs.status().is_success() {
+        return Err(auth_error(format!("token service {} answered HTTP {}", authurl, auth_rs.status().as_u16())));
+    }
 
-    let user: String = match cred.get::<String>("user") {
-        Ok(user) => user,
+    let body = match auth_rs.json::<TokenResponse>().await {
+        Ok(a) => a,
         Err(e) => {
-            warn!("In '{}', config key 'user' does not exist: {}", cred_file, e);
-            return None;
+            return Err(auth_error(format!("token response is not valid json: {e}")));
         }
     };
-    let secret: String = match cred.get::<String>("secret") {
-        Ok(secret) => secret,
-        Err(e) => {
-            warn!("In '{}', config key 'secret' does not exist: {}", cred_file, e);
-            return None;
-        }
+    let token = match body.bearer() {
+        Some(t) => t.to_string(),
+        None => return Err(auth_error("no token in token service response".to_string())),
     };
-    Some(RegistryCredential {user, secret})
-}
-
-#[derive(Clone, Debug)]
-pub struct RegistryCredential {
-    user: String,
-    secret: String
+    TOKEN_CACHE.insert(scope, token.clone(), body.lifetime(OffsetDateTime::now_utc()));
+    Ok(token)
 }
@@ -1,6 +1,6 @@
 use crate::consts::APP_NAME;
 use actix_web_prom::{PrometheusMetrics, PrometheusMetricsBuilder};
-use prometheus::GaugeVec;
+use prometheus::{GaugeVec, IntCounter, IntCounterVec};
 lazy_static! {
         // setup prometheus
     pub static ref STATIC_PROM: PrometheusMetrics = PrometheusMetricsBuilder::new(APP_NAME)
@@ -13,6 +13,17 @@ lazy_static! {
         &["crate_version", "git_hash"]
     )
     .unwrap();
+    pub static ref LOOKUP_ERRORS: IntCounterVec = register_int_counter_vec!(
+        format!("{}_lookup_errors_total",APP_NAME),
+        "image platform lookups that failed, by reason",
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref LOOKUPS_COALESCED: IntCounter = register_int_counter!(
+        format!("{}_lookups_coalesced_total",APP_NAME),
+        "image platform lookups that waited for an identical lookup already in flight"
+    )
+    .unwrap();
 }
 
 pub fn register_metrics() {
@@ -20,4 +31,12 @@ pub fn register_metrics() {
         .registry
         .register(Box::new(APPVER.clone()))
         .expect("couldn't register appver metric");
+    STATIC_PROM
+        .registry
+        .register(Box::new(LOOKUP_ERRORS.clone()))
+        .expect("couldn't register lookup errors metric");
+    STATIC_PROM
+        .registry
+        .register(Box::new(LOOKUPS_COALESCED.clone()))
+        .expect("couldn't register lookups coalesced metric");
 }
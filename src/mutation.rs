@@ -4,8 +4,15 @@ use actix_web::{post, web};
 use base64::{engine::general_purpose, Engine as _};
 use serde_json::{json, Value};
 use std::collections::HashMap;
-use crate::manifest::validate_manifest;
+use crate::consts::{APP_NAME, DEFAULT_LOOKUP_PARALLELISM};
+use crate::credentials::PullSecrets;
+use crate::errors::LookupError;
+use crate::kubernetes::KubernetesClient;
+use crate::manifest::{validate_manifest, ImagePlatforms};
+use crate::platform::Platform;
+use crate::registry::RegistryClient;
 use array_tool::vec::Union;
+use futures_util::stream::{self, StreamExt};
 
 
 fn generate_error_response(uid: String, msg: &str) -> AdmissionReview {
@@ -26,10 +33,28 @@ fn generate_error_response(uid: String, msg: &str) -> AdmissionReview {
     review
 }
 
+/// resolve_images looks up every image at once, with at most `parallelism` lookups running at a time.
+async fn resolve_images(
+    client: &RegistryClient,
+    images: Vec<String>,
+    parallelism: usize,
+    secrets: &PullSecrets,
+) -> HashMap<String, Result<ImagePlatforms, LookupError>> {
+    stream::iter(images)
+        .map(|image| async move {
+            let result = validate_manifest(client, image.clone(), secrets).await;
+            (image, result)
+        })
+        .buffer_unordered(parallelism.max(1))
+        .collect()
+        .await
+}
 
 #[post("/mutate")]
 pub async fn mutate_handler(
     incoming_review: web::Json<AdmissionReview>,
+    client: web::Data<RegistryClient>,
+    kubernetes: Option<web::Data<KubernetesClient>>,
 ) -> web::Json<AdmissionReview> {
     let req = incoming_review.request.clone().unwrap();
     let object = req.object.clone().unwrap();
@@ -95,30 +120,80 @@ pub async fn mutate_handler(
         };
 
 
-        for architecture in supported_architectures {
-            let container_count: u16 = containers.len() as u16;
-            let mut match_count: u16 = 0;
-            for container in containers {
-                let obj: HashMap<String, Value> = serde_json::from_value(container.clone()).unwrap();
-                let image = obj.get("image").unwrap().as_str().unwrap();
-
-                let arches = match validate_manifest(image.to_string()).await {
-                    Some(a) => a,
-                    None => {
-                        warn!("Can't find architecture for image {}", image);
-                        vec![]
+        // supported_architectures entries may be bare architectures (arm64) or full platforms (linux/arm/v7).
+        let supported_platforms: Vec<Platform> = supported_architectures
+            .iter()
+            .filter_map(|a| match a.parse::<Platform>() {
+                Ok(p) => Some(p),
+                Err(e) => {
+                    warn!("ignoring supported architecture '{a}': {e}");
+                    None
+                }
+            })
+            .collect();
+
+        // every image is looked up once, concurrently, and each platform is then decided from the same answers.
+        let mut images: Vec<String> = Vec::new();
+        for container in containers {
+            let obj: HashMap<String, Value> = serde_json::from_value(container.clone()).unwrap();
+            let image = obj.get("image").unwrap().as_str().unwrap().to_string();
+            if !images.contains(&image) {
+                images.push(image);
+            }
+        }
+        let lookup_parallelism: usize = match SETTINGS.read().unwrap().get::<usize>("lookup_parallelism") {
+            Ok(p) => p,
+            Err(_) => DEFAULT_LOOKUP_PARALLELISM,
+        };
+        // with pull secrets turned on, images are looked up with the credentials the kubelet will pull them with.
+        let secrets = match &kubernetes {
+            Some(kubernetes) => {
+                let namespace = req
+                    .namespace
+                    .clone()
+                    .or_else(|| object.get("metadata").unwrap().get("namespace").and_then(|n| n.as_str()).map(|n| n.to_string()))
+                    .unwrap_or_else(|| "default".to_string());
+                kubernetes.pull_secrets(&namespace, spec).await
+            }
+            None => PullSecrets::default(),
+        };
+        let lookups = resolve_images(&client, images.clone(), lookup_parallelism, &secrets).await;
+
+        let mut warnings: Vec<String> = Vec::new();
+        let mut image_platforms: HashMap<String, Vec<Platform>> = HashMap::new();
+        for image in &images {
+            let platforms = match lookups.get(image) {
+                Some(Ok(a)) => a.platforms.clone(),
+                Some(Err(e)) => {
+                    warn!("Can't find architecture for image {}: {}", image, e);
+                    // a typo'd image is worth telling the user about; it will fail to pull as well.
+                    if matches!(e, LookupError::NotFound { .. } | LookupError::InvalidReference { .. }) {
+                        let warning = format!("{}: {}", APP_NAME, e);
+                        if !warnings.contains(&warning) {
+                            warnings.push(warning);
+                        }
                     }
-                };
+                    vec![]
+                }
+                None => vec![],
+            };
+            image_platforms.insert(image.clone(), platforms);
+        }
 
-                if arches.contains(&architecture) {
-                    info!("HIT: {image} has an image of type {architecture}");
+        let mut tolerated_architectures: Vec<String> = Vec::new();
+        for platform in supported_platforms {
+            let mut match_count: usize = 0;
+            for image in &images {
+                if image_platforms[image].iter().any(|p| platform.matches(p)) {
+                    info!("HIT: {image} has an image of type {platform}");
                     match_count += 1;
                 } else {
-                    info!("MISS: image {image} doesn't contain an {architecture} image");
+                    info!("MISS: image {image} doesn't contain an {platform} image");
                 };
-
             }
-                if match_count == container_count {
+                // the toleration only carries the architecture, so several platforms may map onto the same one.
+                if match_count == images.len() && !tolerated_architectures.contains(&platform.architecture) {
+                    let architecture = platform.architecture.clone();
                     let mut arch_toleration = toleration_config.clone();
                     arch_toleration.entry("value".to_string())
                         .and_modify(|val| *val = architecture.clone()).or_insert(architecture.clone());
@@ -129,10 +204,15 @@ pub async fn mutate_handler(
                         "path": "/spec/tolerations/-",
                         "value": arch_toleration
                     }));
+                    tolerated_architectures.push(architecture);
                 };
 
         }
 
+        if !warnings.is_empty() {
+            response.warnings = Some(warnings);
+        }
+
         let tolerations = spec.get("tolerations");
         if tolerations.is_none() {
             // no previous tolerations, so we need to patch it in
@@ -0,0 +1,155 @@
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+
+// operating systems we recognize when a platform string only has one component, see containerd's platforms package.
+const KNOWN_OS: &[&str] = &[
+    "aix", "android", "darwin", "dragonfly", "freebsd", "illumos", "ios", "js", "linux", "netbsd", "openbsd",
+    "plan9", "solaris", "windows", "zos",
+];
+
+/// Platform is the os/architecture/variant an image is built for, or that a node can run.  Values are always kept
+/// normalized the same way containerd normalizes them, so two platforms can be compared field by field.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize, Default)]
+pub struct Platform {
+    /// os is the operating system, e.g. linux or windows
+    pub os: String,
+    /// architecture is the cpu architecture, e.g. amd64 or arm64
+    pub architecture: String,
+    /// variant is the cpu variant, e.g. v7 for 32-bit arm.  None when the architecture's default variant applies.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    /// os_version is the operating system version, only set by windows images
+    #[serde(rename = "os.version", skip_serializing_if = "Option::is_none")]
+    pub os_version: Option<String>,
+}
+
+impl Platform {
+    pub fn new(os: &str, architecture: &str, variant: Option<&str>) -> Platform {
+        let (architecture, variant) = normalize_arch(architecture, variant.unwrap_or(""));
+        Platform {
+            os: normalize_os(os),
+            architecture,
+            variant,
+            os_version: None,
+        }
+    }
+
+    /// from_json reads a platform from an image index `platform` object or from an image config blob, which both use
+    /// the same field names.
+    pub fn from_json(json: &Value) -> Option<Platform> {
+        let architecture = json.get("architecture").and_then(|a| a.as_str())?;
+        let os = json.get("os").and_then(|o| o.as_str()).unwrap_or("");
+        let variant = json.get("variant").and_then(|v| v.as_str());
+        let mut platform = Platform::new(os, architecture, variant);
+        platform.os_version = json.get("os.version").and_then(|v| v.as_str()).map(|v| v.to_string());
+        Some(platform)
+    }
+
+    /// matches reports whether an image built for `image` can run on a node of this platform.  Like containerd, a
+    /// node also accepts images built for older variants of its own architecture (arm/v7 runs arm/v6).
+    pub fn matches(&self, image: &Platform) -> bool {
+        if self.os != image.os || self.architecture != image.architecture {
+            return false;
+        }
+        if self.variant == image.variant {
+            return true;
+        }
+        match (
+            variant_level(&self.architecture, &self.variant),
+            variant_level(&image.architecture, &image.variant),
+        ) {
+            (Some(node), Some(image)) => image <= node,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = anyhow::Error;
+
+    /// parses `arch`, `os/arch` or `os/arch/variant`.  A bare architecture is assumed to be linux, which keeps
+    /// older `supported_architectures = ["arm64"]` configs working.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.trim().split('/').collect();
+        if parts.iter().any(|p| p.is_empty()) {
+            bail!("invalid platform '{}'", s);
+        }
+        match parts.as_slice() {
+            [single] => {
+                if KNOWN_OS.contains(&single.to_lowercase().as_str()) {
+                    bail!("platform '{}' has no architecture", s);
+                }
+                Ok(Platform::new("linux", single, None))
+            }
+            [os, arch] => Ok(Platform::new(os, arch, None)),
+            [os, arch, variant] => Ok(Platform::new(os, arch, Some(*variant))),
+            _ => bail!("platform '{}' has too many components", s),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+fn normalize_os(os: &str) -> String {
+    let os = os.to_lowercase();
+    match os.as_str() {
+        "macos" => "darwin".to_string(),
+        _ => os,
+    }
+}
+
+/// normalize_arch applies containerd's architecture and variant normalization rules.
+fn normalize_arch(architecture: &str, variant: &str) -> (String, Option<String>) {
+    let architecture = architecture.to_lowercase();
+    let variant = variant.to_lowercase();
+    let (architecture, variant) = match architecture.as_str() {
+        "i386" => ("386".to_string(), "".to_string()),
+        "x86_64" | "x86-64" | "amd64" => {
+            let variant = if variant == "v1" { "".to_string() } else { variant };
+            ("amd64".to_string(), variant)
+        }
+        "aarch64" | "arm64" => {
+            let variant = match variant.as_str() {
+                "8" | "v8" | "v8.0" => "".to_string(),
+                _ => variant,
+            };
+            ("arm64".to_string(), variant)
+        }
+        "armhf" => ("arm".to_string(), "v7".to_string()),
+        "armel" => ("arm".to_string(), "v6".to_string()),
+        "arm" => {
+            let variant = match variant.as_str() {
+                "" | "7" => "v7".to_string(),
+                "5" | "6" | "8" => format!("v{}", variant),
+                _ => variant,
+            };
+            ("arm".to_string(), variant)
+        }
+        _ => (architecture, variant),
+    };
+    if variant.is_empty() {
+        (architecture, None)
+    } else {
+        (architecture, Some(variant))
+    }
+}
+
+/// variant_level ranks the variants of architectures whose newer variants can run older ones.
+fn variant_level(architecture: &str, variant: &Option<String>) -> Option<u32> {
+    match (architecture, variant.as_deref()) {
+        ("amd64", None) => Some(1),
+        ("amd64", Some(v)) | ("arm", Some(v)) => v.strip_prefix('v')?.parse().ok(),
+        _ => None,
+    }
+}
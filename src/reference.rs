@@ -0,0 +1,265 @@
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+use thiserror::Error;
+
+// the registry images without one come from, and the name it used to go by.
+pub const DEFAULT_DOMAIN: &str = "docker.io";
+const LEGACY_DEFAULT_DOMAIN: &str = "index.docker.io";
+// docker hub's official images live under library/, even though nobody spells it out.
+const OFFICIAL_REPOSITORY_PREFIX: &str = "library/";
+pub const DEFAULT_TAG: &str = "latest";
+const NAME_TOTAL_LENGTH_MAX: usize = 255;
+const TAG_LENGTH_MAX: usize = 128;
+
+/// ReferenceError explains why a string isn't an image reference, in the words distribution uses.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ReferenceError {
+    #[error("repository name must have at least one component")]
+    Empty,
+    #[error("invalid reference format")]
+    InvalidFormat,
+    #[error("repository name must be lowercase")]
+    NameContainsUppercase,
+    #[error("repository name must not be more than {NAME_TOTAL_LENGTH_MAX} characters")]
+    NameTooLong,
+    #[error("cannot specify 64-byte hexadecimal strings")]
+    Identifier,
+    #[error("invalid registry host '{0}'")]
+    InvalidDomain(String),
+    #[error("invalid tag '{0}'")]
+    InvalidTag(String),
+    #[error("invalid digest '{0}'")]
+    InvalidDigest(String),
+}
+
+/// ImageReference is an image reference normalized the way docker and containerd normalize them, following the
+/// grammar of github.com/distribution/reference: `docker.io` is filled in for images without a registry, docker
+/// hub's single-component names get `library/`, and registry hosts are lowercased.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ImageReference {
+    /// domain is the registry host, with its port if it has one, e.g. `docker.io` or `[::1]:5000`
+    pub domain: String,
+    /// path is the repository within the registry, e.g. `library/nginx`
+    pub path: String,
+    pub tag: Option<String>,
+    /// digest pins the manifest, e.g. `sha256:<hex>`
+    pub digest: Option<String>,
+}
+
+impl ImageReference {
+    pub fn parse(s: &str) -> Result<ImageReference, ReferenceError> {
+        if s.is_empty() {
+            return Err(ReferenceError::Empty);
+        }
+        if s.len() == 64 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            return Err(ReferenceError::Identifier);
+        }
+        let (rest, digest) = match s.split_once('@') {
+            Some((rest, digest)) => {
+                validate_digest(digest)?;
+                (rest, Some(digest.to_string()))
+            }
+            None => (s, None),
+        };
+        // a colon after the last slash starts the tag; one before it belongs to the registry's port.
+        let (name, tag) = match rest.rfind(':') {
+            Some(i) if !rest[i..].contains('/') => (&rest[..i], Some(&rest[i + 1..])),
+            _ => (rest, None),
+        };
+        if let Some(tag) = tag {
+            validate_tag(tag)?;
+        }
+        if name.is_empty() {
+            return Err(ReferenceError::InvalidFormat);
+        }
+
+        let (domain, remainder) = match name.split_once('/') {
+            Some((first, remainder)) if is_domain(first) => {
+                validate_domain(first)?;
+                (first.to_lowercase(), remainder)
+            }
+            _ => (DEFAULT_DOMAIN.to_string(), name),
+        };
+        if remainder.to_lowercase() != remainder {
+            return Err(ReferenceError::NameContainsUppercase);
+        }
+        if remainder.is_empty() || !remainder.split('/').all(is_path_component) {
+            return Err(ReferenceError::InvalidFormat);
+        }
+        let domain = if domain == LEGACY_DEFAULT_DOMAIN { DEFAULT_DOMAIN.to_string() } else { domain };
+        let path = if domain == DEFAULT_DOMAIN && !remainder.contains('/') {
+            format!("{OFFICIAL_REPOSITORY_PREFIX}{remainder}")
+        } else {
+            remainder.to_string()
+        };
+        if domain.len() + 1 + path.len() > NAME_TOTAL_LENGTH_MAX {
+            return Err(ReferenceError::NameTooLong);
+        }
+        Ok(ImageReference {
+            domain,
+            path,
+            tag: tag.map(|t| t.to_string()),
+            digest,
+        })
+    }
+
+    /// name is the fully-qualified repository, e.g. `docker.io/library/nginx`.
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.domain, self.path)
+    }
+
+    /// reference is what to ask the registry for: the digest if the image is pinned to one, even when it also has
+    /// a tag, and otherwise the tag, which defaults to `latest`.
+    pub fn reference(&self) -> &str {
+        self.digest.as_deref().or(self.tag.as_deref()).unwrap_or(DEFAULT_TAG)
+    }
+
+    /// with_default_tag gives a reference with neither tag nor digest the `latest` tag.
+    pub fn with_default_tag(mut self) -> ImageReference {
+        if self.tag.is_none() && self.digest.is_none() {
+            self.tag = Some(DEFAULT_TAG.to_string());
+        }
+        self
+    }
+}
+
+impl fmt::Display for ImageReference {
+    /// formats the canonical form of the reference, e.g. `docker.io/library/nginx:1.23@sha256:<hex>`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{tag}")?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{digest}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ImageReference {
+    type Err = ReferenceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ImageReference::parse(s)
+    }
+}
+
+/// is_domain says whether the first component of a name is a registry host rather than part of the repository:
+/// it has a dot or a port, is `localhost`, or has upper-case letters, which repositories can't.
+pub fn is_domain(component: &str) -> bool {
+    component.contains('.')
+        || component.contains(':')
+        || component == "localhost"
+        || component.to_lowercase() != component
+}
+
+/// validate_domain checks `host[:port]`, where host is a dns name, an ipv4 address or a bracketed ipv6 address.
+fn validate_domain(domain: &str) -> Result<(), ReferenceError> {
+    let invalid = || ReferenceError::InvalidDomain(domain.to_string());
+    let (host_ok, port) = match domain.strip_prefix('[') {
+        Some(bracketed) => {
+            let (address, after) = bracketed.split_once(']').ok_or_else(invalid)?;
+            let port = match after {
+                "" => None,
+                _ => Some(after.strip_prefix(':').ok_or_else(invalid)?),
+            };
+            (address.parse::<Ipv6Addr>().is_ok(), port)
+        }
+        None => {
+            let (host, port) = match domain.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (domain, None),
+            };
+            (!host.is_empty() && host.split('.').all(is_domain_component), port)
+        }
+    };
+    if !host_ok {
+        return Err(invalid());
+    }
+    match port {
+        Some(p) if p.is_empty() || !p.bytes().all(|b| b.is_ascii_digit()) => Err(invalid()),
+        _ => Ok(()),
+    }
+}
+
+/// is_domain_component matches `[a-zA-Z0-9]|[a-zA-Z0-9][a-zA-Z0-9-]*[a-zA-Z0-9]`.
+fn is_domain_component(component: &str) -> bool {
+    !component.is_empty()
+        && !component.starts_with('-')
+        && !component.ends_with('-')
+        && component.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+/// is_path_component matches runs of `[a-z0-9]` joined by one of the separators `.`, `_`, `__` or any number
+/// of `-`.
+fn is_path_component(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    let mut i = 0;
+    loop {
+        let start = i;
+        while i < bytes.len() && matches!(bytes[i], b'a'..=b'z' | b'0'..=b'9') {
+            i += 1;
+        }
+        if i == start {
+            return false;
+        }
+        if i == bytes.len() {
+            return true;
+        }
+        let separator = &component[i..];
+        let length = if separator.starts_with("__") {
+            2
+        } else if separator.starts_with('.') || separator.starts_with('_') {
+            1
+        } else {
+            separator.bytes().take_while(|b| *b == b'-').count()
+        };
+        if length == 0 {
+            return false;
+        }
+        i += length;
+    }
+}
+
+/// validate_tag checks `[\w][\w.-]{0,127}`.
+fn validate_tag(tag: &str) -> Result<(), ReferenceError> {
+    let word = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+    let valid = match tag.as_bytes().split_first() {
+        Some((first, rest)) => {
+            word(*first) && tag.len() <= TAG_LENGTH_MAX && rest.iter().all(|b| word(*b) || *b == b'.' || *b == b'-')
+        }
+        None => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(ReferenceError::InvalidTag(tag.to_string()))
+    }
+}
+
+/// validate_digest checks `algorithm:encoded`.  sha256 and sha512 digests must be lowercase hex of the right
+/// length, and other algorithms at least 32 hex characters.
+fn validate_digest(digest: &str) -> Result<(), ReferenceError> {
+    let valid = match digest.split_once(':') {
+        Some((algorithm, encoded)) => {
+            let algorithm_ok = algorithm.split(['+', '.', '_', '-']).all(|c| {
+                c.as_bytes().first().is_some_and(|b| b.is_ascii_alphabetic()) && c.bytes().all(|b| b.is_ascii_alphanumeric())
+            });
+            let lower_hex = encoded.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+            algorithm_ok
+                && match algorithm {
+                    "sha256" => encoded.len() == 64 && lower_hex,
+                    "sha512" => encoded.len() == 128 && lower_hex,
+                    _ => encoded.len() >= 32 && encoded.bytes().all(|b| b.is_ascii_hexdigit()),
+                }
+        }
+        None => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(ReferenceError::InvalidDigest(digest.to_string()))
+    }
+}
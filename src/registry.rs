@@ -0,0 +1,506 @@
+use crate::consts::{ACTUAL_DOCKER_REGISTRY, APP_NAME};
+use crate::hosts::{hosts_files, HostsFile};
+use crate::tls::{certs_d_files, client_config, TlsFiles};
+use crate::SETTINGS;
+use actix_web::http::{header, Uri};
+use awc::{Client, ClientRequest, Connector};
+use rustls::ClientConfig;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+lazy_static! {
+    pub static ref REGISTRIES: Registries = Registries::from_settings();
+    static ref CONCURRENCY_LIMITS: Mutex<HashMap<String, Arc<Semaphore>>> = Mutex::new(HashMap::new());
+}
+
+/// RegistryClientSettings is the `[registry_client]` table of tolerable.toml.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RegistryClientSettings {
+    /// connect_timeout_seconds is how long opening a connection to a registry, tls handshake included, may take
+    pub connect_timeout_seconds: u64,
+    /// read_timeout_seconds is how long a registry may take to answer a request once it has been sent
+    pub read_timeout_seconds: u64,
+    /// keep_alive_seconds is how long an idle connection is kept around for the next request to the same registry
+    pub keep_alive_seconds: u64,
+    /// max_connections is the most connections a worker keeps open at once
+    pub max_connections: usize,
+    /// certs_dirs are containerd/docker style `certs.d` directories to read per-registry certificates and
+    /// `hosts.toml` mirror configuration from
+    pub certs_dirs: Vec<String>,
+}
+
+impl Default for RegistryClientSettings {
+    fn default() -> Self {
+        RegistryClientSettings {
+            connect_timeout_seconds: 5,
+            read_timeout_seconds: 30,
+            keep_alive_seconds: 60,
+            max_connections: 100,
+            certs_dirs: vec!["/etc/containerd/certs.d".to_string(), "/etc/docker/certs.d".to_string()],
+        }
+    }
+}
+
+impl RegistryClientSettings {
+    pub fn from_settings() -> RegistryClientSettings {
+        SETTINGS.read().unwrap().get::<RegistryClientSettings>("registry_client").unwrap_or_default()
+    }
+}
+
+/// RegistryClient is the http client every registry request goes through.  Each worker builds one when it starts,
+/// and connections are pooled per registry host, so lookups reuse connections instead of handshaking every time.
+/// Registries with their own CAs or client certificates get a client of their own, since awc has one tls config
+/// per client.
+#[derive(Clone)]
+pub struct RegistryClient {
+    client: Client,
+    tls_clients: HashMap<String, Client>,
+    timeouts: HashMap<String, Duration>,
+}
+
+impl RegistryClient {
+    pub fn new(settings: &RegistryClientSettings, registries: &Registries) -> RegistryClient {
+        let mut tls_files: HashMap<String, TlsFiles> = HashMap::new();
+        for dir in &settings.certs_dirs {
+            for (host, files) in certs_d_files(Path::new(dir)) {
+                // the first directory that knows a registry wins, as with containerd's config_path.
+                tls_files.entry(host).or_insert(files);
+            }
+        }
+        for (registry, hosts) in registries.hosts_files() {
+            // like containerd, a hosts.toml takes over from the loose certificates in its directory.
+            let upstream = registries.upstream_endpoint(registry, &registries.get(registry), Some(hosts));
+            if !hosts.server_tls.is_empty() {
+                tls_files.insert(upstream.host, hosts.server_tls.clone());
+            }
+            for host in &hosts.hosts {
+                if !host.tls.is_empty() {
+                    let mirror = registries.mirror_endpoint(&host.url, host.override_path, true);
+                    tls_files.insert(mirror.host, host.tls.clone());
+                }
+            }
+        }
+        let mut timeouts: HashMap<String, Duration> = HashMap::new();
+        for (host, registry) in registries.iter() {
+            let endpoint = registry.endpoint_host().unwrap_or_else(|| host.clone());
+            if let Some(timeout) = registry.timeout_seconds {
+                timeouts.insert(endpoint.clone(), Duration::from_secs(timeout));
+            }
+            let files = tls_files.entry(endpoint).or_default();
+            if let Some(ca_file) = &registry.ca_file {
+                files.ca_files = vec![PathBuf::from(ca_file)];
+            }
+            match (&registry.client_cert_file, &registry.client_key_file) {
+                (Some(cert), Some(key)) => files.client_cert = Some((PathBuf::from(cert), PathBuf::from(key))),
+                (None, None) => {}
+                _ => error!("registry {host} needs both client_cert_file and client_key_file, ignoring them"),
+            }
+        }
+
+        let mut tls_clients: HashMap<String, Client> = HashMap::new();
+        for (host, files) in tls_files.iter().filter(|(_, f)| !f.is_empty()) {
+            match client_config(files) {
+                Ok(config) => {
+                    debug!("registry {host} gets its own tls config: {:?}", files);
+                    tls_clients.insert(host.clone(), build_client(settings, Some(config)));
+                }
+                Err(e) => error!("unable to set up tls for registry {host}, using the defaults: {e:#}"),
+            }
+        }
+        RegistryClient {
+            client: build_client(settings, None),
+            tls_clients,
+            timeouts,
+        }
+    }
+
+    pub fn from_settings() -> RegistryClient {
+        RegistryClient::new(&RegistryClientSettings::from_settings(), &REGISTRIES)
+    }
+
+    pub fn get(&self, url: &str) -> ClientRequest {
+        let host = url_host(url);
+        self.with_timeout(&host, self.client_for(&host).get(url))
+    }
+
+    pub fn head(&self, url: &str) -> ClientRequest {
+        let host = url_host(url);
+        self.with_timeout(&host, self.client_for(&host).head(url))
+    }
+
+    pub fn post(&self, url: &str) -> ClientRequest {
+        let host = url_host(url);
+        self.with_timeout(&host, self.client_for(&host).post(url))
+    }
+
+    fn client_for(&self, host: &str) -> &Client {
+        self.tls_clients.get(host).unwrap_or(&self.client)
+    }
+
+    fn with_timeout(&self, host: &str, request: ClientRequest) -> ClientRequest {
+        match self.timeouts.get(host) {
+            Some(timeout) => request.timeout(*timeout),
+            None => request,
+        }
+    }
+}
+
+/// url_host is the host and port of a url, lowercased, or empty if it has none.
+fn url_host(url: &str) -> String {
+    url.parse::<Uri>()
+        .ok()
+        .and_then(|u| u.authority().map(|a| a.as_str().to_lowercase()))
+        .unwrap_or_default()
+}
+
+fn build_client(settings: &RegistryClientSettings, tls: Option<ClientConfig>) -> Client {
+    let mut connector = Connector::new()
+        .timeout(Duration::from_secs(settings.connect_timeout_seconds))
+        .conn_keep_alive(Duration::from_secs(settings.keep_alive_seconds))
+        .limit(settings.max_connections);
+    if let Some(config) = tls {
+        connector = connector.rustls(Arc::new(config));
+    }
+    Client::builder()
+        .connector(connector)
+        .timeout(Duration::from_secs(settings.read_timeout_seconds))
+        .add_default_header((header::USER_AGENT, user_agent()))
+        .finish()
+}
+
+/// user_agent is what registries see us as, e.g. `tolerable/0.2.1`.
+pub fn user_agent() -> String {
+    format!("{}/{}", APP_NAME, env!("CARGO_PKG_VERSION"))
+}
+
+/// RegistrySettings is one `[registries."<host>"]` table of tolerable.toml, e.g. `[registries."registry.local:5000"]`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RegistrySettings {
+    /// enabled = false stops tolerable from looking up images from this registry at all
+    pub enabled: bool,
+    /// endpoint is the host (and port) actually contacted, when it isn't the one in image names.  It may start with
+    /// `http://` or `https://` to pin the scheme.
+    pub endpoint: Option<String>,
+    /// mirrors are tried in order before the registry itself, e.g. `https://harbor.example.com/v2/dockerhub-proxy`.
+    /// `/v2` is added to a mirror's path unless the path already has it.
+    pub mirrors: Vec<String>,
+    /// aliases are other registry names images use for this registry, e.g. `docker.io` for `registry-1.docker.io`
+    pub aliases: Vec<String>,
+    /// library_prefix turns single-component image names into `library/<name>`.  Unset means docker hub only.
+    pub library_prefix: Option<bool>,
+    /// timeout_seconds replaces registry_client's read_timeout_seconds for this registry
+    pub timeout_seconds: Option<u64>,
+    /// retries is how many times a lookup that failed for a transient reason is tried again
+    pub retries: u32,
+    /// concurrency is the most lookups against this registry running at once.  Unset means no limit.
+    pub concurrency: Option<usize>,
+    /// auth is how to authenticate: `auto` asks the registry, `anonymous` never sends credentials and `basic` always
+    /// sends them as basic auth
+    pub auth: AuthMode,
+    /// credential_file replaces `<registry_credential_path>/<registry>.toml` for this registry
+    pub credential_file: Option<String>,
+    /// insecure talks to the registry over plain http.  Unset means only loopback registries do, like containerd.
+    pub insecure: Option<bool>,
+    /// ca_file is a pem bundle of CAs to trust for this registry, on top of the public roots
+    pub ca_file: Option<String>,
+    /// client_cert_file and client_key_file are the pem certificate and key for registries that want mutual tls
+    pub client_cert_file: Option<String>,
+    pub client_key_file: Option<String>,
+}
+
+impl Default for RegistrySettings {
+    fn default() -> Self {
+        RegistrySettings {
+            enabled: true,
+            endpoint: None,
+            mirrors: vec![],
+            aliases: vec![],
+            library_prefix: None,
+            timeout_seconds: None,
+            retries: 0,
+            concurrency: None,
+            auth: AuthMode::default(),
+            credential_file: None,
+            insecure: None,
+            ca_file: None,
+            client_cert_file: None,
+            client_key_file: None,
+        }
+    }
+}
+
+impl RegistrySettings {
+    /// endpoint_host is the endpoint without its scheme, if one is set.
+    pub fn endpoint_host(&self) -> Option<String> {
+        self.endpoint.as_ref().map(|e| {
+            e.trim_start_matches("https://")
+                .trim_start_matches("http://")
+                .trim_end_matches('/')
+                .to_lowercase()
+        })
+    }
+}
+
+/// AuthMode is the `auth` setting of a registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    #[default]
+    Auto,
+    Anonymous,
+    Basic,
+}
+
+/// Endpoint is one place a registry's manifests can be fetched from: one of its mirrors, or the registry itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    /// host is the host, with its port if it has one, that requests go to
+    pub host: String,
+    /// api_root is the url the registry api lives under, e.g. `https://quay.io/v2`
+    pub api_root: String,
+    /// resolve says whether tags may be resolved here.  Mirrors without it only serve manifests by digest.
+    pub resolve: bool,
+    /// mirror is false for the registry itself
+    pub mirror: bool,
+}
+
+impl Endpoint {
+    /// ping_url is the `/v2/` base of the registry behind the endpoint.  It is always at the root of the host, even
+    /// for a mirror whose api_root has a path of its own.
+    pub fn ping_url(&self) -> String {
+        let scheme = self.api_root.split_once("://").map(|(scheme, _)| scheme).unwrap_or("https");
+        format!("{scheme}://{}/v2/", self.host)
+    }
+
+    /// repository is the name the registry behind the endpoint knows `image_name` by.  A mirror serving from a path
+    /// below /v2, such as a Harbor proxy project at `https://harbor.example.com/v2/dockerhub-proxy`, prefixes it.
+    pub fn repository(&self, image_name: &str) -> String {
+        let base = self.ping_url();
+        let prefix = format!("{}/", self.api_root.trim_end_matches('/'))
+            .strip_prefix(&base)
+            .map(|p| p.trim_matches('/').to_string())
+            .unwrap_or_default();
+        if prefix.is_empty() {
+            image_name.to_string()
+        } else {
+            format!("{prefix}/{image_name}")
+        }
+    }
+}
+
+/// Registries holds the per-registry settings, keyed by host (and port, if it has one), along with any
+/// containerd hosts.toml files for them.
+#[derive(Debug, Clone, Default)]
+pub struct Registries {
+    registries: HashMap<String, RegistrySettings>,
+    hosts: HashMap<String, HostsFile>,
+}
+
+impl Registries {
+    pub fn new(registries: HashMap<String, RegistrySettings>) -> Registries {
+        Registries {
+            registries: registries
+                .into_iter()
+                .map(|(k, v)| (registry_host(&k), v))
+                .collect(),
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// with_hosts_files adds the hosts.toml files of the registries, keyed by host as in a certs.d directory.
+    pub fn with_hosts_files(mut self, hosts: HashMap<String, HostsFile>) -> Registries {
+        self.hosts = hosts;
+        self
+    }
+
+    pub fn from_settings() -> Registries {
+        let registries = match SETTINGS.read().unwrap().get::<HashMap<String, RegistrySettings>>("registries") {
+            Ok(r) => Registries::new(r),
+            Err(_) => Registries::default(),
+        };
+        let mut hosts: HashMap<String, HostsFile> = HashMap::new();
+        for dir in &RegistryClientSettings::from_settings().certs_dirs {
+            for (host, file) in hosts_files(Path::new(dir)) {
+                // the first directory that knows a registry wins, as with containerd's config_path.
+                hosts.entry(host).or_insert(file);
+            }
+        }
+        registries.with_hosts_files(hosts)
+    }
+
+    pub fn hosts_files(&self) -> impl Iterator<Item = (&String, &HostsFile)> {
+        self.hosts.iter()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &RegistrySettings)> {
+        self.registries.iter()
+    }
+
+    /// get returns the settings for `registry`, or the defaults if it has none.
+    pub fn get(&self, registry: &str) -> RegistrySettings {
+        self.registries.get(&registry.to_lowercase()).cloned().unwrap_or_default()
+    }
+
+    /// resolve finds the registry an image's registry name refers to, directly or through an alias, and returns
+    /// its name and settings.
+    pub fn resolve(&self, registry: &str) -> (String, RegistrySettings) {
+        let registry = registry.to_lowercase();
+        if let Some(settings) = self.registries.get(&registry) {
+            return (registry, settings.clone());
+        }
+        if let Some((name, settings)) = self
+            .registries
+            .iter()
+            .find(|(_, s)| s.aliases.iter().any(|a| a.eq_ignore_ascii_case(&registry)))
+        {
+            return (name.clone(), settings.clone());
+        }
+        let host = registry_host(&registry);
+        if host != registry {
+            return (host.clone(), self.get(&host));
+        }
+        (registry, RegistrySettings::default())
+    }
+
+    /// for_endpoint returns the settings of the registry served from `endpoint`.
+    pub fn for_endpoint(&self, endpoint: &str) -> RegistrySettings {
+        let endpoint = endpoint.to_lowercase();
+        match self
+            .registries
+            .iter()
+            .find(|(name, s)| s.endpoint_host().as_deref().unwrap_or(name.as_str()) == endpoint)
+        {
+            Some((_, settings)) => settings.clone(),
+            None => RegistrySettings::default(),
+        }
+    }
+
+    pub fn is_insecure(&self, endpoint: &str) -> bool {
+        let settings = self.for_endpoint(endpoint);
+        match settings.endpoint.as_deref() {
+            Some(e) if e.starts_with("http://") => true,
+            Some(e) if e.starts_with("https://") => false,
+            _ => settings.insecure.unwrap_or_else(|| is_loopback(endpoint)),
+        }
+    }
+
+    /// base_url is where the registry's api lives, e.g. `https://quay.io` or `http://localhost:5001`.
+    pub fn base_url(&self, endpoint: &str) -> String {
+        let scheme = if self.is_insecure(endpoint) { "http" } else { "https" };
+        format!("{}://{}", scheme, endpoint)
+    }
+
+    /// endpoints are where to look for a registry's manifests, in the order to try them: the mirrors from its
+    /// settings, then those from its hosts.toml, then the registry itself.
+    pub fn endpoints(&self, registry: &str, settings: &RegistrySettings) -> Vec<Endpoint> {
+        let hosts = self.hosts.get(&registry.to_lowercase());
+        let mut endpoints: Vec<Endpoint> = settings
+            .mirrors
+            .iter()
+            .map(|m| {
+                let override_path = m.trim_end_matches('/').ends_with("/v2") || m.contains("/v2/");
+                self.mirror_endpoint(m, override_path, true)
+            })
+            .collect();
+        if let Some(hosts) = hosts {
+            for host in hosts.hosts.iter().filter(|h| h.can_pull() || h.can_resolve()) {
+                endpoints.push(self.mirror_endpoint(&host.url, host.override_path, host.can_resolve()));
+            }
+        }
+        endpoints.push(self.upstream_endpoint(registry, settings, hosts));
+        endpoints
+    }
+
+    /// upstream_endpoint is the registry itself: its endpoint setting, else the server of its hosts.toml, else
+    /// its own name.
+    fn upstream_endpoint(&self, registry: &str, settings: &RegistrySettings, hosts: Option<&HostsFile>) -> Endpoint {
+        let mut endpoint = match (settings.endpoint_host(), hosts.and_then(|h| h.server.as_ref())) {
+            (None, Some(server)) => self.mirror_endpoint(server, false, true),
+            (host, _) => {
+                let host = host.unwrap_or_else(|| registry.to_lowercase());
+                Endpoint {
+                    api_root: format!("{}/v2", self.base_url(&host)),
+                    host,
+                    resolve: true,
+                    mirror: false,
+                }
+            }
+        };
+        endpoint.mirror = false;
+        endpoint
+    }
+
+    /// mirror_endpoint turns a mirror url into an endpoint.  Without a scheme, the mirror's host decides between
+    /// http and https the same way a registry's does, and `/v2` goes after the path unless `override_path` says
+    /// the path is already the api root.
+    fn mirror_endpoint(&self, url: &str, override_path: bool, resolve: bool) -> Endpoint {
+        let (scheme, rest) = match url.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme.to_lowercase()), rest),
+            None => (None, url),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (host, path) = match rest.split_once('/') {
+            Some((host, path)) => (host.to_lowercase(), format!("/{path}")),
+            None => (rest.to_lowercase(), String::new()),
+        };
+        let base = match scheme {
+            Some(scheme) => format!("{scheme}://{host}"),
+            None => self.base_url(&host),
+        };
+        let api_root = if override_path { format!("{base}{path}") } else { format!("{base}{path}/v2") };
+        Endpoint {
+            host,
+            api_root,
+            resolve,
+            mirror: true,
+        }
+    }
+}
+
+/// registry_host is the name a registry is known by in settings, hosts.toml files and certs.d directories: its
+/// name lowercased, with docker hub's names swapped for the host it is actually served from.
+pub fn registry_host(registry: &str) -> String {
+    let registry = registry.to_lowercase();
+    match registry.as_str() {
+        // see consts.rs for commentary
+        "docker.io" | "index.docker.io" => ACTUAL_DOCKER_REGISTRY.to_string(),
+        _ => registry,
+    }
+}
+
+/// concurrency_limit is the semaphore shared by every lookup against `endpoint`, sized by its concurrency setting.
+pub fn concurrency_limit(endpoint: &str, limit: usize) -> Arc<Semaphore> {
+    CONCURRENCY_LIMITS
+        .lock()
+        .unwrap()
+        .entry(endpoint.to_lowercase())
+        .or_insert_with(|| Arc::new(Semaphore::new(limit.max(1))))
+        .clone()
+}
+
+/// is_loopback says whether a registry host, with or without a port, is this machine.
+pub fn is_loopback(registry: &str) -> bool {
+    let host = if let Some(bracketed) = registry.strip_prefix('[') {
+        // [::1]:5000
+        bracketed.split(']').next().unwrap_or("")
+    } else if registry.matches(':').count() > 1 {
+        // a bare ipv6 address has no room for a port
+        registry
+    } else {
+        registry.split(':').next().unwrap_or("")
+    };
+    if host.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+    match host.parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback(),
+        Err(_) => false,
+    }
+}
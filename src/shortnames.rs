@@ -0,0 +1,110 @@
+use crate::read_setting_string;
+use crate::reference::{is_domain, DEFAULT_DOMAIN};
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// where CRI-O and podman read their short-name configuration from.
+const DEFAULT_REGISTRIES_CONF: &str = "/etc/containers/registries.conf";
+
+lazy_static! {
+    pub static ref SHORT_NAMES: RegistriesConf = RegistriesConf::from_settings();
+}
+
+/// RegistriesConf is the short-name part of a containers-registries.conf(5) file: where unqualified image names
+/// are searched for, and the aliases that pin some of them to one registry.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct RegistriesConf {
+    /// unqualified_search_registries are tried in order for names without a registry.  Unset means docker.io.
+    pub unqualified_search_registries: Option<Vec<String>>,
+    /// aliases map short names, e.g. `nginx`, to fully-qualified ones, e.g. `docker.io/library/nginx`
+    pub aliases: HashMap<String, String>,
+}
+
+impl RegistriesConf {
+    /// from_settings reads the file at `registries_conf` (`/etc/containers/registries.conf` by default) and the
+    /// drop-ins in the `.d` directory next to it.  Without any of them, short names are docker hub names.
+    pub fn from_settings() -> RegistriesConf {
+        let path = read_setting_string("registries_conf").unwrap_or_else(|_| DEFAULT_REGISTRIES_CONF.to_string());
+        RegistriesConf::load(Path::new(&path))
+    }
+
+    /// load reads a registries.conf and its drop-ins, each of which replaces the search registries and adds to the
+    /// aliases of the ones before it.  Files that can't be read are logged and skipped.
+    pub fn load(path: &Path) -> RegistriesConf {
+        let mut files: Vec<PathBuf> = vec![path.to_path_buf()];
+        let drop_ins = PathBuf::from(format!("{}.d", path.display()));
+        if let Ok(entries) = fs::read_dir(&drop_ins) {
+            let mut entries: Vec<PathBuf> = entries
+                .flatten()
+                .map(|e| e.path())
+                .filter(|p| p.extension().and_then(|e| e.to_str()) == Some("conf"))
+                .collect();
+            entries.sort();
+            files.extend(entries);
+        }
+        let mut conf = RegistriesConf::default();
+        for file in files.iter().filter(|f| f.is_file()) {
+            let parsed = fs::read_to_string(file)
+                .with_context(|| format!("cannot read {}", file.display()))
+                .and_then(|text| RegistriesConf::parse(&text));
+            match parsed {
+                Ok(c) => conf.merge(c),
+                Err(e) => error!("ignoring {}: {e:#}", file.display()),
+            }
+        }
+        conf
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<RegistriesConf> {
+        toml::from_str(text).context("not a valid registries.conf")
+    }
+
+    fn merge(&mut self, other: RegistriesConf) {
+        if other.unqualified_search_registries.is_some() {
+            self.unqualified_search_registries = other.unqualified_search_registries;
+        }
+        self.aliases.extend(other.aliases);
+    }
+
+    /// candidates are the fully-qualified images a name could mean, in the order to try them.  Qualified names
+    /// only mean themselves, an alias wins over searching, and otherwise each search registry is a candidate.
+    pub fn candidates(&self, image: &str) -> Vec<String> {
+        if is_qualified(image) {
+            return vec![image.to_string()];
+        }
+        let (name, suffix) = split_name(image);
+        if let Some(alias) = self.aliases.get(name) {
+            return vec![format!("{}{}", alias.trim_end_matches('/'), suffix)];
+        }
+        match &self.unqualified_search_registries {
+            Some(registries) if !registries.is_empty() => {
+                registries.iter().map(|r| format!("{}/{}", r.trim_end_matches('/'), image)).collect()
+            }
+            _ => vec![format!("{DEFAULT_DOMAIN}/{image}")],
+        }
+    }
+}
+
+/// is_qualified says whether an image names its registry, which is the case when its first component looks like
+/// a host.
+pub fn is_qualified(image: &str) -> bool {
+    match image.split_once('/') {
+        Some((first, _)) => is_domain(first),
+        None => false,
+    }
+}
+
+/// split_name separates the repository name of an image from its `:tag` and/or `@digest`.
+fn split_name(image: &str) -> (&str, &str) {
+    let without_digest = image.split('@').next().unwrap_or(image);
+    let last_component = without_digest.rfind('/').map(|i| i + 1).unwrap_or(0);
+    let end = match without_digest[last_component..].find(':') {
+        Some(i) => last_component + i,
+        None => without_digest.len(),
+    };
+    image.split_at(end)
+}
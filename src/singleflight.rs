@@ -0,0 +1,83 @@
+use crate::errors::LookupError;
+use crate::manifest::ImagePlatforms;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+type LookupResult = Option<Result<ImagePlatforms, LookupError>>;
+
+lazy_static! {
+    pub static ref IN_FLIGHT: InFlight = InFlight::new();
+}
+
+/// InFlight tracks the lookups currently talking to a registry, so that concurrent admissions of the same image
+/// wait for one lookup instead of each starting their own.
+#[derive(Default)]
+pub struct InFlight {
+    lookups: Mutex<HashMap<String, watch::Receiver<LookupResult>>>,
+}
+
+/// Flight is what a caller of InFlight::join has to do next.
+pub enum Flight<'a> {
+    /// Leader means nobody else is looking this image up; do it and report back through the guard.
+    Leader(FlightGuard<'a>),
+    /// Follower means another caller is already looking this image up; wait for its answer.
+    Follower(watch::Receiver<LookupResult>),
+}
+
+/// FlightGuard belongs to the caller doing the lookup.  Dropping it without completing, e.g. because the admission
+/// request was cancelled, releases the waiting callers so one of them can take over.
+pub struct FlightGuard<'a> {
+    flights: &'a InFlight,
+    key: String,
+    sender: watch::Sender<LookupResult>,
+}
+
+impl InFlight {
+    pub fn new() -> InFlight {
+        InFlight {
+            lookups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// join either starts a flight for `key` or attaches to the one already running.
+    pub fn join(&self, key: &str) -> Flight<'_> {
+        let mut lookups = self.lookups.lock().unwrap();
+        if let Some(receiver) = lookups.get(key) {
+            return Flight::Follower(receiver.clone());
+        }
+        let (sender, receiver) = watch::channel(None);
+        lookups.insert(key.to_string(), receiver);
+        Flight::Leader(FlightGuard {
+            flights: self,
+            key: key.to_string(),
+            sender,
+        })
+    }
+}
+
+impl FlightGuard<'_> {
+    /// complete hands the result to every caller waiting on this flight.
+    pub fn complete(self, result: Result<ImagePlatforms, LookupError>) {
+        // nobody listening is fine, it just means nobody joined.
+        let _ = self.sender.send(Some(result));
+    }
+}
+
+impl Drop for FlightGuard<'_> {
+    fn drop(&mut self) {
+        self.flights.lookups.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// wait_for_flight waits for the leader of a flight to finish.  None means the leader went away without an answer.
+pub async fn wait_for_flight(mut receiver: watch::Receiver<LookupResult>) -> LookupResult {
+    loop {
+        if let Some(result) = receiver.borrow_and_update().as_ref() {
+            return Some(result.clone());
+        }
+        if receiver.changed().await.is_err() {
+            return receiver.borrow().clone();
+        }
+    }
+}
@@ -1,4 +1,30 @@
 #[cfg(test)]
+mod temp_dir;
+#[cfg(test)]
 mod test_bl;
 #[cfg(test)]
 mod test_serde;
+#[cfg(test)]
+mod test_platform;
+#[cfg(test)]
+mod test_cache;
+#[cfg(test)]
+mod test_singleflight;
+#[cfg(test)]
+mod test_auth;
+#[cfg(test)]
+mod test_registry;
+#[cfg(test)]
+mod test_tls;
+#[cfg(test)]
+mod test_hosts;
+#[cfg(test)]
+mod test_shortnames;
+#[cfg(test)]
+mod test_reference;
+#[cfg(test)]
+mod test_credentials;
+#[cfg(test)]
+mod test_credential_provider;
+#[cfg(test)]
+mod test_kubernetes;
@@ -0,0 +1,238 @@
+use crate::auth::{
+    auth_challenge, bearer_challenge, parse_challenges, AuthChallenge, AuthSettings, BearerChallenge, TokenCache,
+    TokenResponse,
+};
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+fn docker_hub() -> BearerChallenge {
+    BearerChallenge {
+        realm: "https://auth.docker.io/token".to_string(),
+        service: "registry.docker.io".to_string(),
+    }
+}
+
+fn token_response(body: &str) -> TokenResponse {
+    serde_json::from_str(body).unwrap()
+}
+
+#[test]
+fn token_response_accepts_either_field() {
+    assert_eq!(token_response(r#"{"token": "abc"}"#).bearer(), Some("abc"));
+    assert_eq!(token_response(r#"{"access_token": "abc"}"#).bearer(), Some("abc"));
+    assert_eq!(token_response(r#"{"token": "abc", "access_token": "def"}"#).bearer(), Some("abc"));
+    assert_eq!(token_response(r#"{"expires_in": 300}"#).bearer(), None);
+}
+
+#[test]
+fn token_lifetime_counts_from_issued_at() {
+    let now = OffsetDateTime::parse("2023-03-10T12:00:00Z", &Rfc3339).unwrap();
+    // issued two minutes ago for five minutes, less the safety margin.
+    let response = token_response(r#"{"token": "abc", "expires_in": 300, "issued_at": "2023-03-10T11:58:00Z"}"#);
+    assert_eq!(response.lifetime(now), Duration::from_secs(170));
+    // no issued_at means it was issued just now, and no expires_in means the spec's 60 seconds.
+    assert_eq!(token_response(r#"{"token": "abc", "expires_in": 300}"#).lifetime(now), Duration::from_secs(290));
+    assert_eq!(token_response(r#"{"token": "abc"}"#).lifetime(now), Duration::from_secs(50));
+    // long expired tokens and clocks from the future.
+    let expired = token_response(r#"{"token": "abc", "expires_in": 60, "issued_at": "2023-03-10T11:00:00Z"}"#);
+    assert_eq!(expired.lifetime(now), Duration::ZERO);
+    let future = token_response(r#"{"token": "abc", "expires_in": 300, "issued_at": "2023-03-10T13:00:00Z"}"#);
+    assert_eq!(future.lifetime(now), Duration::from_secs(290));
+    // absurd lifetimes are capped at a day rather than overflowing.
+    for expires_in in ["10000000000000", "18446744073709551615"] {
+        let forever = token_response(&format!(r#"{{"token": "abc", "expires_in": {expires_in}}}"#));
+        assert_eq!(forever.lifetime(now), Duration::from_secs(86400 - 10));
+    }
+}
+
+#[test]
+fn token_cache_shares_tokens_by_scope() {
+    let cache = TokenCache::new(AuthSettings::default());
+    assert_eq!(cache.challenge("registry-1.docker.io"), None);
+    cache.remember_challenge("registry-1.docker.io", AuthChallenge::Bearer(docker_hub()));
+    let nginx = match cache.challenge("registry-1.docker.io") {
+        Some(AuthChallenge::Bearer(challenge)) => challenge.scope_for("library/nginx"),
+        other => panic!("expected a bearer challenge, got {:?}", other),
+    };
+    assert_eq!(nginx.scope, "repository:library/nginx:pull");
+    assert_eq!(
+        nginx.url(),
+        "https://auth.docker.io/token?service=registry.docker.io&scope=repository:library/nginx:pull"
+    );
+
+    cache.insert(nginx.clone(), "abc".to_string(), Duration::from_secs(300));
+    assert_eq!(cache.get(&docker_hub().scope_for("library/nginx")), Some("abc".to_string()));
+    assert_eq!(cache.get(&docker_hub().scope_for("library/redis")), None);
+    // nor with a token asked for with someone's credentials.
+    let mut with_credential = docker_hub().scope_for("library/nginx");
+    with_credential.credential = "0123456789abcdef".to_string();
+    assert_eq!(cache.get(&with_credential), None);
+    assert_eq!(nginx.url(), with_credential.url());
+
+    // a token that is already expired isn't kept.
+    let redis = docker_hub().scope_for("library/redis");
+    cache.insert(redis.clone(), "def".to_string(), Duration::ZERO);
+    assert_eq!(cache.get(&redis), None);
+}
+
+#[test]
+fn bearer_challenges_from_real_registries() {
+    // (registry, header, realm, service, scope)
+    let cases = vec![
+        (
+            "docker hub",
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/nginx:pull""#,
+            "https://auth.docker.io/token",
+            "registry.docker.io",
+            Some("repository:library/nginx:pull"),
+        ),
+        (
+            "ghcr",
+            r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:petergrace/tolerable:pull""#,
+            "https://ghcr.io/token",
+            "ghcr.io",
+            Some("repository:petergrace/tolerable:pull"),
+        ),
+        (
+            "quay",
+            r#"Bearer realm="https://quay.io/v2/auth",service="quay.io",scope="repository:metallb/controller:pull""#,
+            "https://quay.io/v2/auth",
+            "quay.io",
+            Some("repository:metallb/controller:pull"),
+        ),
+        (
+            "harbor /v2/ ping, no scope",
+            r#"Bearer realm="https://harbor.example.com/service/token",service="harbor-registry""#,
+            "https://harbor.example.com/service/token",
+            "harbor-registry",
+            None,
+        ),
+        (
+            "gitlab, with an error parameter",
+            r#"Bearer realm="https://gitlab.com/jwt/auth",service="container_registry",scope="repository:group/project:pull",error="insufficient_scope""#,
+            "https://gitlab.com/jwt/auth",
+            "container_registry",
+            Some("repository:group/project:pull"),
+        ),
+        (
+            "reordered and spaced out",
+            r#"Bearer scope="repository:library/nginx:pull", service="registry.docker.io", realm="https://auth.docker.io/token""#,
+            "https://auth.docker.io/token",
+            "registry.docker.io",
+            Some("repository:library/nginx:pull"),
+        ),
+        (
+            "several challenges",
+            r#"Basic realm="Registry Realm", Bearer realm="https://registry.example.com/token",service="registry.example.com""#,
+            "https://registry.example.com/token",
+            "registry.example.com",
+            None,
+        ),
+        (
+            "lowercase scheme, unquoted service",
+            r#"bearer realm="https://registry.example.com/token", service=registry.example.com"#,
+            "https://registry.example.com/token",
+            "registry.example.com",
+            None,
+        ),
+    ];
+    for (name, header, realm, service, scope) in cases {
+        let (challenge, challenge_scope) = bearer_challenge(header).unwrap_or_else(|| panic!("{name}: no bearer challenge"));
+        assert_eq!(challenge.realm, realm, "{name}");
+        assert_eq!(challenge.service, service, "{name}");
+        assert_eq!(challenge_scope.as_deref(), scope, "{name}");
+    }
+}
+
+#[test]
+fn challenges_without_a_bearer_token() {
+    // ecr wants basic auth.
+    let ecr = r#"Basic realm="https://123456789012.dkr.ecr.us-east-1.amazonaws.com/",service="ecr.amazonaws.com""#;
+    assert_eq!(bearer_challenge(ecr), None);
+    let challenges = parse_challenges(ecr);
+    assert_eq!(challenges.len(), 1);
+    assert_eq!(challenges[0].scheme, "basic");
+    assert_eq!(challenges[0].param("service"), Some("ecr.amazonaws.com"));
+    assert_eq!(auth_challenge(ecr), Some((AuthChallenge::Basic, None)));
+
+    for header in ["", "Bearer", "Bearer service=\"registry.example.com\"", ",,, =\"\""] {
+        assert_eq!(bearer_challenge(header), None, "{header}");
+    }
+}
+
+#[test]
+fn challenges_with_escapes_and_token68() {
+    let challenges = parse_challenges(r#"Newauth realm="apps", type=1, title="Login to \"apps\"", Basic realm="simple""#);
+    assert_eq!(challenges.len(), 2);
+    assert_eq!(challenges[0].scheme, "newauth");
+    assert_eq!(challenges[0].param("type"), Some("1"));
+    assert_eq!(challenges[0].param("title"), Some(r#"Login to "apps""#));
+    assert_eq!(challenges[1].param("realm"), Some("simple"));
+
+    let challenges = parse_challenges("Negotiate YIIFjgYGKwYBBQUCoIIFgjCC==, Bearer realm=\"https://registry.example.com/token\"");
+    assert_eq!(challenges.len(), 2);
+    assert_eq!(challenges[0].token68.as_deref(), Some("YIIFjgYGKwYBBQUCoIIFgjCC=="));
+    assert_eq!(challenges[1].param("realm"), Some("https://registry.example.com/token"));
+}
+
+#[test]
+fn token_urls_skip_empty_parameters() {
+    let harbor = BearerChallenge {
+        realm: "https://harbor.example.com/service/token".to_string(),
+        service: "".to_string(),
+    };
+    assert_eq!(
+        harbor.scope_for("library/nginx").url(),
+        "https://harbor.example.com/service/token?scope=repository:library/nginx:pull"
+    );
+    let with_query = BearerChallenge {
+        realm: "https://registry.example.com/token?account=ci".to_string(),
+        service: "registry.example.com".to_string(),
+    };
+    assert_eq!(
+        with_query.scope_for("library/nginx").url(),
+        "https://registry.example.com/token?account=ci&service=registry.example.com&scope=repository:library/nginx:pull"
+    );
+}
+
+#[test]
+fn token_urls_escape_parameters() {
+    let odd = BearerChallenge {
+        realm: "https://registry.example.com/token".to_string(),
+        service: "Example Registry&co=1".to_string(),
+    };
+    assert_eq!(
+        odd.scope_for("library/nginx").url(),
+        "https://registry.example.com/token?service=Example%20Registry%26co%3D1&scope=repository:library/nginx:pull"
+    );
+}
+
+#[test]
+fn auth_challenge_follows_the_challenge_type() {
+    // registry:2 behind htpasswd
+    assert_eq!(auth_challenge(r#"Basic realm="Registry Realm""#), Some((AuthChallenge::Basic, None)));
+    // offered both, bearer wins
+    let both = r#"Basic realm="Artifactory Realm", Bearer realm="https://example.jfrog.io/artifactory/api/docker/docker/v2/token",service="example.jfrog.io""#;
+    match auth_challenge(both) {
+        Some((AuthChallenge::Bearer(c), None)) => assert_eq!(c.service, "example.jfrog.io"),
+        other => panic!("expected a bearer challenge, got {:?}", other),
+    }
+    assert_eq!(auth_challenge(r#"Negotiate YIIFjgYGKwYBBQUCoIIFgjCC=="#), None);
+}
+
+#[test]
+fn token_cache_forgets_discovery_results() {
+    let cache = TokenCache::new(AuthSettings {
+        discovery_ttl_seconds: 0,
+        ..AuthSettings::default()
+    });
+    cache.remember_challenge("registry.example.com", AuthChallenge::Anonymous);
+    assert_eq!(cache.challenge("registry.example.com"), None);
+
+    let cache = TokenCache::new(AuthSettings::default());
+    cache.remember_challenge("registry.example.com", AuthChallenge::Anonymous);
+    cache.remember_challenge("registry-1.docker.io", AuthChallenge::Bearer(docker_hub()));
+    assert_eq!(cache.challenge("registry.example.com"), Some(AuthChallenge::Anonymous));
+    assert_eq!(cache.challenge("registry-1.docker.io"), Some(AuthChallenge::Bearer(docker_hub())));
+}
@@ -6,38 +6,117 @@ fn init() {
 
 use crate::models::AdmissionReview;
 use crate::mutation::mutate_handler;
-use actix_web::{test, App};
+use actix_web::{test, web, App};
 use serde_json;
 use std::fs;
-use crate::manifest::validate_manifest;
+use crate::manifest::{
+    get_config_platforms, get_version_2_entries, is_image_manifest, normalize_image, validate_manifest, verify_digest,
+    IndexEntry,
+};
+use crate::credentials::PullSecrets;
+use crate::errors::LookupError;
+use crate::platform::Platform;
+use crate::registry::{user_agent, RegistryClient};
+use serde_json::json;
 
 #[test]
 async fn test_manifest_validator_v2() {
-    let expected = "arm64".to_string();
-    let results = validate_manifest("nginx:latest".to_string()).await.unwrap();
-    assert!(results.contains(&expected));
+    let expected: Platform = "linux/arm64".parse().unwrap();
+    let results = validate_manifest(&RegistryClient::from_settings(), "nginx:latest".to_string(), &PullSecrets::default()).await.unwrap().platforms;
+    assert!(results.iter().any(|p| expected.matches(p)));
 }
 #[test]
 async fn test_manifest_validator_v2_quay() {
-    let expected = "arm64".to_string();
-    let results = validate_manifest("quay.io/metallb/controller:latest".to_string()).await.unwrap();
-    assert!(results.contains(&expected));
+    let expected: Platform = "linux/arm64".parse().unwrap();
+    let results = validate_manifest(&RegistryClient::from_settings(), "quay.io/metallb/controller:latest".to_string(), &PullSecrets::default()).await.unwrap().platforms;
+    assert!(results.iter().any(|p| expected.matches(p)));
 }
 #[test]
 // redundant because I fixed docker.io schema v1 lookup, but, didn't want to remove the code just
 // in case
 async fn test_manifest_validator_v1() {
-    let expected = "amd64".to_string();
-    let results = match validate_manifest("nginx:latest".to_string()).await {
-        Some(s) => s,
-        None => vec![]
+    let expected: Platform = "linux/amd64".parse().unwrap();
+    let results = match validate_manifest(&RegistryClient::from_settings(), "nginx:latest".to_string(), &PullSecrets::default()).await {
+        Ok(s) => s.platforms,
+        Err(_) => vec![]
     };
-    assert!(results.contains(&expected));
+    assert!(results.iter().any(|p| expected.matches(p)));
+}
+
+#[test]
+async fn test_manifest_validator_single_arch_config() {
+    // a plain image manifest has no platform list, so the platform comes from the config blob instead.
+    let manifest = json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+        "config": {
+            "mediaType": "application/vnd.docker.container.image.v1+json",
+            "size": 1472,
+            "digest": "sha256:3f57d9401f8d42f986df300f0c69192fc41da28ccc8d797829467780db3dd741"
+        },
+        "layers": []
+    });
+    assert!(is_image_manifest(&manifest, ""));
+    let config = json!({"architecture": "arm64", "os": "linux", "variant": "v8", "config": {}});
+    let results = get_config_platforms(&config).unwrap();
+    assert_eq!(results, vec![Platform::new("linux", "arm64", None)]);
+}
+
+#[test]
+async fn test_manifest_validator_not_found() {
+    let result = validate_manifest(&RegistryClient::from_settings(), "quay.io/metallb/this-image-does-not-exist:latest".to_string(), &PullSecrets::default()).await;
+    match result {
+        Err(LookupError::NotFound { .. }) | Err(LookupError::Unauthorized { .. }) => {}
+        other => panic!("expected a not found error, got {:?}", other),
+    }
+}
+
+#[test]
+async fn test_registry_user_agent() {
+    assert_eq!(user_agent(), format!("tolerable/{}", env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+async fn test_manifest_digest_references() {
+    let digest = "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    let target = normalize_image(&format!("nginx:1.23@{digest}")).unwrap();
+    assert_eq!(target.image_name, "library/nginx");
+    assert_eq!(target.reference, digest);
+    let target = normalize_image("quay.io/metallb/controller:latest").unwrap();
+    assert_eq!(target.image_name, "metallb/controller");
+    assert_eq!(target.reference, "latest");
+
+    assert!(verify_digest(digest, b"abc"));
+    assert!(!verify_digest(digest, b"abd"));
+    assert!(!verify_digest("not-a-digest", b"abc"));
+    // digests we have no way of checking are refused rather than trusted.
+    let unknown = "foo:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    assert!(!verify_digest(unknown, b"abc"));
+    assert!(matches!(
+        normalize_image(&format!("nginx@{unknown}")),
+        Err(LookupError::InvalidReference { .. })
+    ));
+}
+
+#[test]
+async fn test_index_skips_attestations() {
+    let index_json = fs::read_to_string("./src/tests/image-index-buildkit.json").expect("Unable to read file!");
+    let index: serde_json::Value = serde_json::from_str(index_json.as_str()).unwrap();
+    let entries = get_version_2_entries(&index).unwrap();
+    assert_eq!(
+        entries,
+        vec![
+            IndexEntry::Platform(Platform::new("linux", "amd64", None)),
+            IndexEntry::Platform(Platform::new("linux", "arm", Some("v7"))),
+            IndexEntry::Nested("sha256:6b4f3e2d1c0b9a8f7e6d5c4b3a2f1e0d9c8b7a6f5e4d3c2b1a0f9e8d7c6b5a4f".to_string()),
+            IndexEntry::Nested("sha256:5a4b3c2d1e0f9a8b7c6d5e4f3a2b1c0d9e8f7a6b5c4d3e2f1a0b9c8d7e6f5a4b".to_string()),
+        ]
+    );
 }
 
 #[actix_web::test]
 async fn test_mutate_handler_non_pod() {
-    let app = test::init_service(App::new().service(mutate_handler)).await;
+    let app = test::init_service(App::new().app_data(web::Data::new(RegistryClient::from_settings())).service(mutate_handler)).await;
     let review_json = fs::read_to_string("./src/tests/admission-review-not-pod.json")
         .expect("Unable to read file!");
     let review: AdmissionReview = serde_json::from_str(review_json.as_str()).unwrap();
@@ -54,7 +133,7 @@ async fn test_mutate_handler_non_pod() {
 #[actix_web::test]
 async fn test_mutate_handler_as_pod_not_arm() {
     std::env::set_var("TOLERABLE_SUPPORTED_ARCHITECTURES","arm64");
-    let app = test::init_service(App::new().service(mutate_handler)).await;
+    let app = test::init_service(App::new().app_data(web::Data::new(RegistryClient::from_settings())).service(mutate_handler)).await;
     let review_json =
         fs::read_to_string("./src/tests/admission-review-pod.json").expect("Unable to read file!");
     let review: AdmissionReview = serde_json::from_str(review_json.as_str()).unwrap();
@@ -70,7 +149,7 @@ async fn test_mutate_handler_as_pod_not_arm() {
 #[actix_web::test]
 async fn test_mutate_handler_as_pod_matches_arm() {
     std::env::set_var("TOLERABLE_SUPPORTED_ARCHITECTURES","arm64");
-    let app = test::init_service(App::new().service(mutate_handler)).await;
+    let app = test::init_service(App::new().app_data(web::Data::new(RegistryClient::from_settings())).service(mutate_handler)).await;
     let review_json = fs::read_to_string("./src/tests/admission-review-pod-match.json")
         .expect("Unable to read file!");
     let review: AdmissionReview = serde_json::from_str(review_json.as_str()).unwrap();
@@ -87,7 +166,7 @@ async fn test_mutate_handler_as_pod_matches_arm() {
 #[actix_web::test]
 async fn test_mutate_handler_half_pod_supports_arm() {
     std::env::set_var("TOLERABLE_SUPPORTED_ARCHITECTURES","arm64");
-    let app = test::init_service(App::new().service(mutate_handler)).await;
+    let app = test::init_service(App::new().app_data(web::Data::new(RegistryClient::from_settings())).service(mutate_handler)).await;
     let review_json =
         fs::read_to_string("./src/tests/admission-review-pod-half-arm.json").expect("Unable to read file!");
     let review: AdmissionReview = serde_json::from_str(review_json.as_str()).unwrap();
@@ -0,0 +1,113 @@
+use crate::cache::{CacheSettings, PlatformCache};
+use crate::errors::LookupError;
+use crate::manifest::ImagePlatforms;
+use crate::platform::Platform;
+
+fn platforms(image: &str) -> ImagePlatforms {
+    ImagePlatforms {
+        image: image.to_string(),
+        digest: "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string(),
+        platforms: vec![Platform::new("linux", "arm64", None)],
+    }
+}
+
+fn not_found(image: &str) -> LookupError {
+    LookupError::NotFound {
+        registry: "registry-1.docker.io".to_string(),
+        repository: image.to_string(),
+        status: 404,
+    }
+}
+
+#[test]
+fn cache_remembers_results_until_they_expire() {
+    let cache = PlatformCache::new(CacheSettings {
+        max_entries: 10,
+        ttl_seconds: 3600,
+        negative_ttl_seconds: 0,
+        stale_seconds: 0,
+    });
+    assert!(cache.is_empty());
+    cache.insert("nginx:latest", Ok(platforms("nginx:latest")));
+    cache.insert("nginx:typo", Err(not_found("library/nginx")));
+    assert_eq!(cache.get("nginx:latest"), Some(Ok(platforms("nginx:latest"))));
+    // a zero negative ttl means the failure is already stale.
+    assert_eq!(cache.get("nginx:typo"), None);
+    assert_eq!(cache.len(), 1);
+    assert!(!cache.is_empty());
+}
+
+#[test]
+fn cache_skips_transient_failures() {
+    let cache = PlatformCache::new(CacheSettings::default());
+    cache.insert(
+        "nginx:latest",
+        Err(LookupError::Unreachable {
+            registry: "registry-1.docker.io".to_string(),
+            message: "connection refused".to_string(),
+        }),
+    );
+    cache.insert(
+        "redis:latest",
+        Err(LookupError::Http {
+            registry: "registry-1.docker.io".to_string(),
+            repository: "library/redis".to_string(),
+            status: 503,
+        }),
+    );
+    cache.insert("nginx:typo", Err(not_found("library/nginx")));
+    assert_eq!(cache.get("nginx:latest"), None);
+    assert_eq!(cache.get("redis:latest"), None);
+    assert_eq!(cache.get("nginx:typo"), Some(Err(not_found("library/nginx"))));
+}
+
+#[test]
+fn cache_evicts_least_recently_used() {
+    let cache = PlatformCache::new(CacheSettings {
+        max_entries: 2,
+        ..CacheSettings::default()
+    });
+    cache.insert("a:latest", Ok(platforms("a:latest")));
+    cache.insert("b:latest", Ok(platforms("b:latest")));
+    assert!(cache.get("a:latest").is_some());
+    cache.insert("c:latest", Ok(platforms("c:latest")));
+    assert_eq!(cache.len(), 2);
+    assert!(cache.get("a:latest").is_some());
+    assert!(cache.get("b:latest").is_none());
+    assert!(cache.get("c:latest").is_some());
+}
+
+#[test]
+fn cache_serves_stale_results_while_one_caller_revalidates() {
+    let cache = PlatformCache::new(CacheSettings {
+        max_entries: 10,
+        ttl_seconds: 0,
+        negative_ttl_seconds: 0,
+        stale_seconds: 3600,
+    });
+    cache.insert("nginx:latest", Ok(platforms("nginx:latest")));
+    cache.insert("nginx:typo", Err(not_found("library/nginx")));
+    assert_eq!(cache.get("nginx:latest"), None);
+    assert_eq!(cache.get_stale("nginx:latest"), Some((platforms("nginx:latest"), true)));
+    // someone is already on it.
+    assert_eq!(cache.get_stale("nginx:latest"), Some((platforms("nginx:latest"), false)));
+    cache.revalidation_failed("nginx:latest");
+    assert_eq!(cache.get_stale("nginx:latest"), Some((platforms("nginx:latest"), true)));
+    // failures are never served stale.
+    assert_eq!(cache.get_stale("nginx:typo"), None);
+}
+
+#[test]
+fn cache_refresh_clears_revalidation() {
+    let cache = PlatformCache::new(CacheSettings {
+        max_entries: 10,
+        ttl_seconds: 0,
+        negative_ttl_seconds: 0,
+        stale_seconds: 3600,
+    });
+    cache.insert("nginx:latest", Ok(platforms("nginx:latest")));
+    assert_eq!(cache.get_stale("nginx:latest"), Some((platforms("nginx:latest"), true)));
+    cache.refresh("nginx:latest");
+    // a zero ttl expires straight away again, and the next caller gets to revalidate.
+    assert_eq!(cache.get_stale("nginx:latest"), Some((platforms("nginx:latest"), true)));
+}
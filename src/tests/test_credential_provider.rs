@@ -0,0 +1,138 @@
+use crate::credential_provider::{image_matches, parse_go_duration, CredentialProviderConfig, CredentialProviders};
+use crate::credentials::RegistryCredential;
+use crate::tests::temp_dir::TempDir;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+#[test]
+fn match_images_globs() {
+    // (glob, image, matches)
+    let cases = vec![
+        ("*.dkr.ecr.*.amazonaws.com", "123456789012.dkr.ecr.us-east-1.amazonaws.com/app", true),
+        ("*.dkr.ecr.*.amazonaws.com", "dkr.ecr.us-east-1.amazonaws.com/app", false),
+        ("*.dkr.ecr.*.amazonaws.com", "a.b.dkr.ecr.us-east-1.amazonaws.com/app", false),
+        ("*.azurecr.io", "myregistry.azurecr.io/team/app", true),
+        ("*-docker.pkg.dev", "europe-docker.pkg.dev/project/repo/app", true),
+        ("gcr.io", "gcr.io/project/app", true),
+        ("gcr.io", "eu.gcr.io/project/app", false),
+        ("registry.io:8080/path", "registry.io:8080/path/app", true),
+        ("registry.io:8080/path", "registry.io/path/app", false),
+        ("registry.io/path", "registry.io/other/app", false),
+        ("https://registry.io", "registry.io/app", true),
+        ("registry?.io", "registry1.io/app", true),
+    ];
+    for (glob, image, matches) in cases {
+        assert_eq!(image_matches(glob, image), matches, "{glob} {image}");
+    }
+}
+
+#[test]
+fn go_durations() {
+    // (text, seconds)
+    let cases = vec![("0", 0.0), ("12h", 43200.0), ("6h0m0s", 21600.0), ("1h30m", 5400.0), ("1.5s", 1.5), ("500ms", 0.5)];
+    for (text, seconds) in cases {
+        assert_eq!(parse_go_duration(text).unwrap(), Duration::from_secs_f64(seconds), "{text}");
+    }
+    for bad in ["", "12", "h", "1d", "1h-1m", "999999999999999999999h"] {
+        assert!(parse_go_duration(bad).is_err(), "{bad}");
+    }
+}
+
+#[test]
+fn credential_provider_config() {
+    let config = CredentialProviderConfig::parse(
+        r#"
+apiVersion: kubelet.config.k8s.io/v1
+kind: CredentialProviderConfig
+providers:
+  - name: ecr-credential-provider
+    matchImages:
+      - "*.dkr.ecr.*.amazonaws.com"
+    defaultCacheDuration: "12h"
+    apiVersion: credentialprovider.kubelet.k8s.io/v1
+    args: [get-credentials]
+    env:
+      - name: AWS_PROFILE
+        value: temp
+"#,
+    )
+    .unwrap();
+    let provider = &config.providers[0];
+    assert_eq!(provider.name, "ecr-credential-provider");
+    assert_eq!(provider.match_images, vec!["*.dkr.ecr.*.amazonaws.com".to_string()]);
+    assert_eq!(provider.default_cache_duration.as_deref(), Some("12h"));
+    assert_eq!(provider.args, vec!["get-credentials".to_string()]);
+    assert_eq!(provider.env[0].name, "AWS_PROFILE");
+
+    for bad in [
+        "providers: [{name: p, apiVersion: v1}]",
+        "providers: [{name: p, matchImages: [a.io]}]",
+        "providers: [{name: ../p, matchImages: [a.io], apiVersion: v1}]",
+        "providers: [{name: p, matchImages: [a.io], apiVersion: v1, defaultCacheDuration: 1d}]",
+        "providers: 1",
+    ] {
+        assert!(CredentialProviderConfig::parse(bad).is_err(), "{bad}");
+    }
+}
+
+fn write_plugin(dir: &Path, name: &str, response: &str) {
+    let path = dir.join(name);
+    // the plugin counts its runs, so tests can tell a cached response from a fresh one.
+    let script = format!(
+        "#!/bin/sh\nread request\necho \"$request\" >> {}\ncat <<'RESPONSE'\n{response}\nRESPONSE\n",
+        dir.join(format!("{name}.runs")).display()
+    );
+    fs::write(&path, script).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+fn runs(dir: &Path, name: &str) -> usize {
+    fs::read_to_string(dir.join(format!("{name}.runs"))).map(|r| r.lines().count()).unwrap_or(0)
+}
+
+#[actix_web::test]
+async fn credential_provider_plugins() {
+    let dir = TempDir::new("credential-provider");
+    write_plugin(
+        &dir,
+        "registry-plugin",
+        r#"{"apiVersion": "credentialprovider.kubelet.k8s.io/v1", "kind": "CredentialProviderResponse", "cacheKeyType": "Registry", "cacheDuration": "1h",
+            "auth": {"*.example.com": {"username": "wide", "password": "1"}, "registry.example.com/team": {"username": "team", "password": "2"}}}"#,
+    );
+    write_plugin(
+        &dir,
+        "image-plugin",
+        r#"{"apiVersion": "credentialprovider.kubelet.k8s.io/v1", "kind": "CredentialProviderResponse", "cacheKeyType": "Image",
+            "auth": {"images.example.org": {"username": "image", "password": "3"}}}"#,
+    );
+    write_plugin(&dir, "wrong-plugin", r#"{"apiVersion": "v1", "kind": "Pod"}"#);
+    let config = CredentialProviderConfig::parse(
+        r#"
+providers:
+  - {name: registry-plugin, matchImages: ["*.example.com"], apiVersion: credentialprovider.kubelet.k8s.io/v1}
+  - {name: image-plugin, matchImages: [images.example.org], apiVersion: credentialprovider.kubelet.k8s.io/v1}
+  - {name: wrong-plugin, matchImages: [wrong.example.net], apiVersion: credentialprovider.kubelet.k8s.io/v1}
+"#,
+    )
+    .unwrap();
+    let providers = CredentialProviders::new(config, &dir);
+    let basic = |user: &str, secret: &str| Some(RegistryCredential::Basic { user: user.to_string(), secret: secret.to_string() });
+
+    assert_eq!(providers.credentials("registry.example.com/team/app").await.unwrap(), basic("team", "2"));
+    assert_eq!(providers.credentials("registry.example.com/other/app").await.unwrap(), basic("wide", "1"));
+    // cached per registry for an hour, so the plugin ran once.
+    assert_eq!(runs(&dir, "registry-plugin"), 1);
+    assert!(fs::read_to_string(dir.join("registry-plugin.runs"))
+        .unwrap()
+        .contains(r#""kind":"CredentialProviderRequest","image":"registry.example.com/team/app""#));
+
+    // without a cache duration, nothing is cached.
+    assert_eq!(providers.credentials("images.example.org/app").await.unwrap(), basic("image", "3"));
+    assert_eq!(providers.credentials("images.example.org/app").await.unwrap(), basic("image", "3"));
+    assert_eq!(runs(&dir, "image-plugin"), 2);
+
+    assert_eq!(providers.credentials("quay.io/app").await.unwrap(), None);
+    assert!(providers.credentials("wrong.example.net/app").await.is_err());
+}
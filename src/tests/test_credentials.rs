@@ -0,0 +1,280 @@
+use crate::credentials::{
+    docker_config_credentials, docker_hostname, docker_path, load_docker_configs, repository_prefixes,
+    run_credential_helper, CredentialCache, CredentialSource, DockerConfig, PullSecrets, RegistryCredential,
+};
+use crate::tests::temp_dir::TempDir;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+fn basic(user: &str, secret: &str) -> Option<RegistryCredential> {
+    Some(RegistryCredential::Basic { user: user.to_string(), secret: secret.to_string() })
+}
+
+#[test]
+fn docker_hostnames() {
+    // (key, host)
+    let cases = vec![
+        ("https://index.docker.io/v1/", "docker.io"),
+        ("index.docker.io", "docker.io"),
+        ("registry-1.docker.io", "docker.io"),
+        ("docker.io", "docker.io"),
+        ("https://quay.io/v2/", "quay.io"),
+        ("http://registry.local:5000", "registry.local:5000"),
+        ("Harbor.Example.com/library", "harbor.example.com"),
+        ("ghcr.io", "ghcr.io"),
+    ];
+    for (key, host) in cases {
+        assert_eq!(docker_hostname(key), host, "{key}");
+    }
+}
+
+#[test]
+fn docker_config_entries() {
+    // dXNlcjpwYXNzOndvcmQ= is user:pass:word
+    let config = DockerConfig::parse(
+        r#"{
+            "auths": {
+                "https://index.docker.io/v1/": {"auth": "dXNlcjpwYXNzOndvcmQ="},
+                "ghcr.io": {"username": "robot", "password": "ghp_secret", "auth": "dXNlcjpwYXNzOndvcmQ="},
+                "https://myregistry.azurecr.io": {"identitytoken": "refresh-me", "auth": "MDAwMDAwMDAtMDAwMC0wMDAwLTAwMDAtMDAwMDAwMDAwMDAwOg=="},
+                "registry.example.com": {"registrytoken": "bearer-me"},
+                "empty.example.com": {},
+                "broken.example.com": {"auth": "not base64!"},
+                "nocolon.example.com": {"auth": "dXNlcg=="}
+            },
+            "credsStore": "desktop"
+        }"#,
+    )
+    .unwrap();
+    // (registry, credential)
+    let cases = vec![
+        ("docker.io", basic("user", "pass:word")),
+        ("index.docker.io", basic("user", "pass:word")),
+        ("ghcr.io", basic("robot", "ghp_secret")),
+        ("myregistry.azurecr.io", Some(RegistryCredential::IdentityToken("refresh-me".to_string()))),
+        ("registry.example.com", Some(RegistryCredential::RegistryToken("bearer-me".to_string()))),
+        ("empty.example.com", None),
+    ];
+    for (registry, credential) in cases {
+        let auth = config.auth_for(registry).unwrap_or_else(|| panic!("{registry}: no entry"));
+        assert_eq!(auth.credential().unwrap(), credential, "{registry}");
+    }
+    assert!(config.auth_for("quay.io").is_none());
+    assert!(config.auth_for("broken.example.com").unwrap().credential().is_err());
+    assert!(config.auth_for("nocolon.example.com").unwrap().credential().is_err());
+    assert!(basic("user", "pass:word").unwrap().basic_auth().is_some());
+    assert!(RegistryCredential::IdentityToken("t".to_string()).basic_auth().is_none());
+}
+
+#[test]
+fn legacy_dockercfg() {
+    let config = DockerConfig::parse(r#"{"https://quay.io": {"auth": "dXNlcjpwYXNzOndvcmQ=", "email": "a@b.c"}}"#).unwrap();
+    assert_eq!(config.auth_for("quay.io").unwrap().credential().unwrap(), basic("user", "pass:word"));
+    assert!(DockerConfig::parse(r#"{"credHelpers": {"gcr.io": "gcr"}}"#).unwrap().auths.is_empty());
+    assert!(DockerConfig::parse(r#""a string""#).is_err());
+    assert!(DockerConfig::parse("not json").is_err());
+}
+
+#[actix_web::test]
+async fn docker_config_files_in_order() {
+    let dir = TempDir::new("docker-config");
+    let first = dir.join("config.json");
+    fs::write(&first, r#"{"auths": {"ghcr.io": {"username": "first", "password": "1"}}}"#).unwrap();
+    let second = dir.join(".dockerconfigjson");
+    fs::write(
+        &second,
+        r#"{"auths": {"ghcr.io": {"username": "second", "password": "2"}, "quay.io": {"username": "second", "password": "2"}}}"#,
+    )
+    .unwrap();
+    let broken = dir.join("broken.json");
+    fs::write(&broken, "{").unwrap();
+    let files: Vec<String> = [dir.join("missing.json"), first, second]
+        .iter()
+        .map(|f| f.display().to_string())
+        .collect();
+
+    let configs = load_docker_configs(&files, "ghcr.io").unwrap();
+    assert_eq!(configs.len(), 2);
+    assert_eq!(docker_config_credentials(&configs, "ghcr.io").await.unwrap(), basic("first", "1"));
+    assert_eq!(docker_config_credentials(&configs, "quay.io").await.unwrap(), basic("second", "2"));
+    assert_eq!(docker_config_credentials(&configs, "docker.io").await.unwrap(), None);
+    assert!(load_docker_configs(&[broken.display().to_string()], "ghcr.io").is_err());
+}
+
+#[test]
+fn docker_configs_are_parsed_again_once_modified() {
+    let dir = TempDir::new("docker-config-reload");
+    let config = dir.join("config.json");
+    fs::write(&config, r#"{"auths": {"ghcr.io": {"username": "old", "password": "1"}}}"#).unwrap();
+    let files = [config.display().to_string()];
+    let user = |configs: &[(String, DockerConfig)]| configs[0].1.auths["ghcr.io"].username.clone();
+    assert_eq!(user(&load_docker_configs(&files, "ghcr.io").unwrap()), Some("old".to_string()));
+
+    fs::write(&config, r#"{"auths": {"ghcr.io": {"username": "new", "password": "2"}}}"#).unwrap();
+    // make sure the change shows, however coarse the filesystem's timestamps are.
+    let later = SystemTime::now() + Duration::from_secs(60);
+    fs::File::options().write(true).open(&config).unwrap().set_modified(later).unwrap();
+    assert_eq!(user(&load_docker_configs(&files, "ghcr.io").unwrap()), Some("new".to_string()));
+}
+
+#[test]
+fn credential_sources() {
+    let config = DockerConfig::parse(
+        r#"{
+            "auths": {"ghcr.io": {"auth": "dXNlcjpwYXNzOndvcmQ="}, "quay.io": {"auth": "dXNlcjpwYXNzOndvcmQ="}},
+            "credHelpers": {"https://index.docker.io/v1/": "pass", "123456789012.dkr.ecr.us-east-1.amazonaws.com": "ecr-login"}
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(config.source_for("docker.io"), Some(CredentialSource::Helper("pass".to_string())));
+    assert_eq!(
+        config.source_for("123456789012.dkr.ecr.us-east-1.amazonaws.com"),
+        Some(CredentialSource::Helper("ecr-login".to_string()))
+    );
+    assert!(matches!(config.source_for("ghcr.io"), Some(CredentialSource::Auth(_))));
+    assert_eq!(config.source_for("gcr.io"), None);
+
+    // the credential store takes over from auths, but not from a registry's own helper.
+    let config = DockerConfig { creds_store: Some("desktop".to_string()), ..config };
+    assert_eq!(config.source_for("ghcr.io"), Some(CredentialSource::Helper("desktop".to_string())));
+    assert_eq!(config.source_for("docker.io"), Some(CredentialSource::Helper("pass".to_string())));
+}
+
+fn write_helper(dir: &Path, name: &str, script: &str) -> String {
+    let path = dir.join(name);
+    fs::write(&path, format!("#!/bin/sh\n{script}")).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path.display().to_string()
+}
+
+#[test]
+fn credential_helper_protocol() {
+    let dir = TempDir::new("credential-helper");
+    let helper = write_helper(
+        &dir,
+        "docker-credential-test",
+        r#"[ "$1" = get ] || exit 2
+read server
+case "$server" in
+  https://index.docker.io/v1/) echo '{"ServerURL": "https://index.docker.io/v1/", "Username": "hub", "Secret": "s3cret"}' ;;
+  token.example.com) echo '{"ServerURL": "token.example.com", "Username": "<token>", "Secret": "refresh-me"}' ;;
+  broken.example.com) echo 'not json' ;;
+  *) echo 'credentials not found in native keychain'; exit 1 ;;
+esac
+"#,
+    );
+    assert_eq!(run_credential_helper(&helper, "https://index.docker.io/v1/").unwrap(), basic("hub", "s3cret"));
+    assert_eq!(
+        run_credential_helper(&helper, "token.example.com").unwrap(),
+        Some(RegistryCredential::IdentityToken("refresh-me".to_string()))
+    );
+    assert_eq!(run_credential_helper(&helper, "quay.io").unwrap(), None);
+    assert!(run_credential_helper(&helper, "broken.example.com").is_err());
+    let failing = write_helper(&dir, "docker-credential-failing", "echo 'keychain is locked' >&2; exit 1");
+    assert!(run_credential_helper(&failing, "quay.io").is_err());
+    assert!(run_credential_helper(&dir.join("missing").display().to_string(), "quay.io").is_err());
+    // more than a pipe holds, before the helper has even read its input.
+    let chatty = write_helper(
+        &dir,
+        "docker-credential-chatty",
+        r#"head -c 200000 /dev/zero | tr '\0' x >&2
+read server
+echo '{"ServerURL": "quay.io", "Username": "chatty", "Secret": "s3cret"}'
+"#,
+    );
+    assert_eq!(run_credential_helper(&chatty, "quay.io").unwrap(), basic("chatty", "s3cret"));
+}
+
+#[test]
+fn credential_cache_ttl() {
+    let cache = CredentialCache::new(Duration::from_secs(60));
+    assert_eq!(cache.get("pass", "docker.io"), None);
+    cache.insert("pass", "docker.io", basic("hub", "s3cret"));
+    cache.insert("pass", "quay.io", None);
+    assert_eq!(cache.get("pass", "docker.io"), Some(basic("hub", "s3cret")));
+    assert_eq!(cache.get("pass", "quay.io"), Some(None));
+    assert_eq!(cache.get("desktop", "docker.io"), None);
+
+    let cache = CredentialCache::new(Duration::ZERO);
+    cache.insert("pass", "docker.io", basic("hub", "s3cret"));
+    assert_eq!(cache.get("pass", "docker.io"), None);
+}
+
+#[test]
+fn pull_secrets_in_order() {
+    let config = |json: &str| DockerConfig::parse(json).unwrap();
+    let secrets = PullSecrets::new(vec![
+        ("team-a/quay".to_string(), config(r#"{"auths": {"quay.io": {"username": "first", "password": "1"}}}"#)),
+        (
+            "team-a/all".to_string(),
+            config(r#"{"auths": {"quay.io": {"username": "second", "password": "2"}, "https://index.docker.io/v1/": {"username": "hub", "password": "3"}, "broken.example.com": {"auth": "!"}}}"#),
+        ),
+    ]);
+    assert_eq!(secrets.credential_for("quay.io", "team-a/app").unwrap(), basic("first", "1"));
+    assert_eq!(secrets.credential_for("docker.io", "team-a/app").unwrap(), basic("hub", "3"));
+    assert_eq!(secrets.credential_for("ghcr.io", "team-a/app").unwrap(), None);
+    let broken = secrets.credential_for("broken.example.com", "team-a/app").unwrap_err().to_string();
+    assert!(broken.contains("team-a/all"), "{broken}");
+}
+
+#[test]
+fn credential_fingerprints() {
+    let fingerprint = basic("user", "secret").unwrap().fingerprint();
+    assert_eq!(fingerprint.len(), 16);
+    assert!(!fingerprint.contains("secret"));
+    assert_eq!(fingerprint, basic("user", "secret").unwrap().fingerprint());
+    assert_ne!(fingerprint, basic("user", "other").unwrap().fingerprint());
+    assert_ne!(
+        RegistryCredential::IdentityToken("t".to_string()).fingerprint(),
+        RegistryCredential::RegistryToken("t".to_string()).fingerprint()
+    );
+}
+
+#[test]
+fn repository_prefixes_longest_first() {
+    assert_eq!(repository_prefixes("team-a/tools/app"), vec!["team-a/tools/app", "team-a/tools", "team-a"]);
+    assert_eq!(repository_prefixes("nginx"), vec!["nginx"]);
+    assert!(repository_prefixes("").is_empty());
+    // (key, path)
+    let cases = vec![
+        ("ghcr.io", ""),
+        ("https://index.docker.io/v1/", ""),
+        ("https://quay.io/v2/", ""),
+        ("ghcr.io/team-a/", "team-a"),
+        ("https://gitlab.example.com/group/sub", "group/sub"),
+    ];
+    for (key, path) in cases {
+        assert_eq!(docker_path(key), path, "{key}");
+    }
+}
+
+#[test]
+fn repository_scoped_entries() {
+    let config = |json: &str| DockerConfig::parse(json).unwrap();
+    let team_a = config(
+        r#"{"auths": {
+            "ghcr.io": {"username": "host", "password": "0"},
+            "ghcr.io/team-a/": {"username": "team-a", "password": "1"},
+            "https://ghcr.io/team-a/tools": {"username": "tools", "password": "2"}
+        }}"#,
+    );
+    assert_eq!(team_a.auth_for("ghcr.io").unwrap().username.as_deref(), Some("host"));
+    assert_eq!(team_a.auth_for_prefix("ghcr.io", "team-a").unwrap().username.as_deref(), Some("team-a"));
+    assert_eq!(team_a.auth_for_prefix("ghcr.io", "team-a/tools").unwrap().username.as_deref(), Some("tools"));
+    assert!(team_a.auth_for_prefix("ghcr.io", "team-b").is_none());
+
+    // the longest prefix wins over the order of the secrets, and the registry's own entry comes last.
+    let secrets = PullSecrets::new(vec![
+        ("team-a/host".to_string(), config(r#"{"auths": {"ghcr.io": {"username": "host", "password": "0"}}}"#)),
+        ("team-a/scoped".to_string(), team_a.clone()),
+    ]);
+    assert_eq!(secrets.credential_for("ghcr.io", "team-a/tools/lint").unwrap(), basic("tools", "2"));
+    assert_eq!(secrets.credential_for("ghcr.io", "team-a/app").unwrap(), basic("team-a", "1"));
+    assert_eq!(secrets.credential_for("ghcr.io", "team-ab/app").unwrap(), basic("host", "0"));
+    assert_eq!(secrets.credential_for("quay.io", "team-a/app").unwrap(), None);
+    // a prefix entry never stands in for the whole registry.
+    let only_scoped = config(r#"{"auths": {"ghcr.io/team-a": {"username": "team-a", "password": "1"}}}"#);
+    assert!(only_scoped.auth_for("ghcr.io").is_none());
+}
@@ -0,0 +1,101 @@
+use crate::hosts::{hosts_files, parse_hosts_toml, HostEntry};
+use crate::tests::temp_dir::TempDir;
+use crate::tls::TlsFiles;
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn hosts_toml_keeps_mirror_order() {
+    let dir = Path::new("/etc/containerd/certs.d/docker.io");
+    let file = parse_hosts_toml(
+        r#"
+        server = "https://registry-1.docker.io"
+        ca = "/etc/pki/upstream.pem"
+
+        [host."https://zot.example.com:5000"]
+          capabilities = ["pull", "resolve"]
+          ca = ["zot-ca.pem"]
+          client = [["/etc/pki/client.cert", "/etc/pki/client.key"]]
+
+        [host."https://harbor.example.com/v2/dockerhub-proxy"]
+          capabilities = ["pull"]
+          override_path = true
+          client = "/etc/pki/harbor-client.pem"
+        "#,
+        dir,
+    )
+    .unwrap();
+    assert_eq!(file.server.as_deref(), Some("https://registry-1.docker.io"));
+    assert_eq!(file.server_tls.ca_files, vec![Path::new("/etc/pki/upstream.pem").to_path_buf()]);
+    // sorted, harbor would come first.
+    assert_eq!(
+        file.hosts,
+        vec![
+            HostEntry {
+                url: "https://zot.example.com:5000".to_string(),
+                capabilities: vec!["pull".to_string(), "resolve".to_string()],
+                override_path: false,
+                tls: TlsFiles {
+                    ca_files: vec![dir.join("zot-ca.pem")],
+                    client_cert: Some(("/etc/pki/client.cert".into(), "/etc/pki/client.key".into())),
+                },
+            },
+            HostEntry {
+                url: "https://harbor.example.com/v2/dockerhub-proxy".to_string(),
+                capabilities: vec!["pull".to_string()],
+                override_path: true,
+                tls: TlsFiles {
+                    ca_files: vec![],
+                    client_cert: Some(("/etc/pki/harbor-client.pem".into(), "/etc/pki/harbor-client.pem".into())),
+                },
+            },
+        ]
+    );
+    assert!(file.hosts[0].can_resolve());
+    assert!(file.hosts[1].can_pull() && !file.hosts[1].can_resolve());
+}
+
+#[test]
+fn hosts_toml_order_ignores_mentions_elsewhere() {
+    let file = parse_hosts_toml(
+        r#"
+        # "https://b.example.com" is the fallback
+        [host."https://a.example.com"]
+          ca = "https://b.example.com"
+        [host.'https://b.example.com']
+        [host."https://\u0061a.example.com"]
+        "#,
+        Path::new("/"),
+    )
+    .unwrap();
+    let urls: Vec<&str> = file.hosts.iter().map(|h| h.url.as_str()).collect();
+    assert_eq!(urls, vec!["https://a.example.com", "https://b.example.com", "https://aa.example.com"]);
+}
+
+#[test]
+fn hosts_toml_defaults_and_errors() {
+    let file = parse_hosts_toml(r#"[host."http://localhost:5001"]"#, Path::new("/")).unwrap();
+    assert_eq!(file.server, None);
+    assert!(file.hosts[0].can_pull() && file.hosts[0].can_resolve());
+    assert!(parse_hosts_toml("", Path::new("/")).unwrap().hosts.is_empty());
+    for bad in ["server = ", "host = 1", r#"ca = 1"#, r#"[host."https://m"]
+client = ["a", "b", "c"]"#] {
+        assert!(parse_hosts_toml(bad, Path::new("/")).is_err(), "{bad}");
+    }
+}
+
+#[test]
+fn hosts_files_in_certs_d() {
+    let dir = TempDir::new("hosts-files");
+    fs::create_dir_all(dir.join("docker.io")).unwrap();
+    fs::write(dir.join("docker.io").join("hosts.toml"), r#"[host."https://mirror.gcr.io"]"#).unwrap();
+    fs::create_dir_all(dir.join("quay.io")).unwrap();
+    fs::write(dir.join("quay.io").join("hosts.toml"), "not toml [").unwrap();
+    fs::create_dir_all(dir.join("ghcr.io")).unwrap();
+    fs::write(dir.join("ghcr.io").join("ca.crt"), "").unwrap();
+
+    let files = hosts_files(&dir);
+    assert_eq!(files.len(), 1);
+    assert_eq!(files["registry-1.docker.io"].hosts[0].url, "https://mirror.gcr.io");
+    assert!(hosts_files(&dir.join("does-not-exist")).is_empty());
+}
@@ -0,0 +1,85 @@
+use crate::credentials::{PullSecrets, RegistryCredential};
+use crate::kubernetes::{secret_docker_config, KubernetesClient, KubernetesSettings};
+use crate::tests::temp_dir::TempDir;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::{json, Value};
+use std::fs;
+
+fn dockerconfigjson(config: Value) -> Value {
+    json!({
+        "type": "kubernetes.io/dockerconfigjson",
+        "data": {".dockerconfigjson": general_purpose::STANDARD.encode(config.to_string())}
+    })
+}
+
+/// fake_api answers like the api server of a cluster with one namespace, team-a.
+async fn fake_api(req: HttpRequest) -> HttpResponse {
+    if req.headers().get("authorization").and_then(|a| a.to_str().ok()) != Some("Bearer fake-token") {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let object = match req.path() {
+        "/api/v1/namespaces/team-a/serviceaccounts/builder" => json!({"imagePullSecrets": [{"name": "quay"}, {"name": "ghcr"}]}),
+        "/api/v1/namespaces/team-a/secrets/ghcr" => {
+            dockerconfigjson(json!({"auths": {"ghcr.io": {"username": "team-a", "password": "ghp_a"}}}))
+        }
+        "/api/v1/namespaces/team-a/secrets/quay" => json!({
+            "type": "kubernetes.io/dockercfg",
+            "data": {".dockercfg": general_purpose::STANDARD.encode(r#"{"quay.io": {"auth": "cXVheTpzZWNyZXQ="}}"#)}
+        }),
+        "/api/v1/namespaces/team-a/secrets/ghcr-other" => {
+            dockerconfigjson(json!({"auths": {"ghcr.io": {"username": "other", "password": "ghp_b"}}}))
+        }
+        "/api/v1/namespaces/team-a/secrets/opaque" => json!({"type": "Opaque", "data": {}}),
+        _ => return HttpResponse::NotFound().finish(),
+    };
+    HttpResponse::Ok().json(object)
+}
+
+#[actix_web::test]
+async fn pull_secrets_from_the_api() {
+    let server = HttpServer::new(|| App::new().default_service(web::to(fake_api)))
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+    let address = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+
+    let dir = TempDir::new("kubernetes");
+    fs::write(dir.join("token"), "fake-token\n").unwrap();
+    let kubernetes = KubernetesClient::new(&KubernetesSettings {
+        pull_secrets: true,
+        api_server: format!("http://{address}/"),
+        token_file: dir.join("token").display().to_string(),
+        ca_file: dir.join("missing-ca.crt").display().to_string(),
+        timeout_seconds: 5,
+    });
+    let basic = |user: &str, secret: &str| Some(RegistryCredential::Basic { user: user.to_string(), secret: secret.to_string() });
+
+    // the pod's own secrets come first, then its service account's; missing and unusable ones are skipped.
+    let spec = json!({
+        "serviceAccountName": "builder",
+        "imagePullSecrets": [{"name": "missing"}, {"name": "opaque"}, {"name": "ghcr-other"}]
+    });
+    let secrets = kubernetes.pull_secrets("team-a", &spec).await;
+    assert_eq!(secrets.credential_for("ghcr.io", "team-a/app").unwrap(), basic("other", "ghp_b"));
+    assert_eq!(secrets.credential_for("quay.io", "team-a/app").unwrap(), basic("quay", "secret"));
+    assert_eq!(secrets.credential_for("docker.io", "team-a/app").unwrap(), None);
+
+    // a pod without a service account gets `default`, which doesn't exist here.
+    assert_eq!(kubernetes.pull_secrets("team-a", &json!({})).await, PullSecrets::default());
+    let secrets = kubernetes.pull_secrets("team-a", &json!({"imagePullSecrets": [{"name": "ghcr"}]})).await;
+    assert_eq!(secrets.credential_for("ghcr.io", "team-a/app").unwrap(), basic("team-a", "ghp_a"));
+
+    // without the token the api server turns us away, and there are no secrets.
+    fs::remove_file(dir.join("token")).unwrap();
+    assert_eq!(kubernetes.pull_secrets("team-a", &spec).await, PullSecrets::default());
+}
+
+#[test]
+fn secret_types() {
+    assert!(secret_docker_config(&dockerconfigjson(json!({"auths": {}}))).is_ok());
+    assert!(secret_docker_config(&json!({"type": "Opaque"})).is_err());
+    assert!(secret_docker_config(&json!({"type": "kubernetes.io/dockerconfigjson", "data": {}})).is_err());
+    assert!(secret_docker_config(&json!({"type": "kubernetes.io/dockerconfigjson", "data": {".dockerconfigjson": "!"}})).is_err());
+}
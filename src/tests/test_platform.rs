@@ -0,0 +1,75 @@
+use crate::platform::Platform;
+use serde_json::json;
+
+#[test]
+fn parse_and_normalize_platforms() {
+    // (input, expected normalized form), following containerd's platform normalization
+    let cases = vec![
+        ("arm64", "linux/arm64"),
+        ("amd64", "linux/amd64"),
+        ("x86_64", "linux/amd64"),
+        ("linux/x86-64", "linux/amd64"),
+        ("linux/amd64/v1", "linux/amd64"),
+        ("linux/amd64/v3", "linux/amd64/v3"),
+        ("linux/aarch64", "linux/arm64"),
+        ("linux/arm64/v8", "linux/arm64"),
+        ("linux/arm64/8", "linux/arm64"),
+        ("linux/arm", "linux/arm/v7"),
+        ("linux/arm/7", "linux/arm/v7"),
+        ("linux/arm/v6", "linux/arm/v6"),
+        ("linux/arm/5", "linux/arm/v5"),
+        ("linux/armhf", "linux/arm/v7"),
+        ("linux/armel", "linux/arm/v6"),
+        ("linux/i386", "linux/386"),
+        ("Linux/ARM64", "linux/arm64"),
+        ("windows/amd64", "windows/amd64"),
+        ("macos/arm64", "darwin/arm64"),
+        ("linux/s390x", "linux/s390x"),
+    ];
+    for (input, expected) in cases {
+        let platform: Platform = input.parse().unwrap();
+        assert_eq!(platform.to_string(), expected, "normalizing {}", input);
+    }
+}
+
+#[test]
+fn reject_invalid_platforms() {
+    for input in ["", "linux", "linux/", "/arm64", "linux/arm/v7/extra"] {
+        assert!(input.parse::<Platform>().is_err(), "expected {:?} to be rejected", input);
+    }
+}
+
+#[test]
+fn platform_from_index_entry() {
+    let platform = Platform::from_json(&json!({
+        "architecture": "amd64",
+        "os": "windows",
+        "os.version": "10.0.17763.4131"
+    }))
+    .unwrap();
+    assert_eq!(platform.os, "windows");
+    assert_eq!(platform.os_version, Some("10.0.17763.4131".to_string()));
+    assert!(Platform::from_json(&json!({"os": "linux"})).is_none());
+}
+
+#[test]
+fn match_complete_platforms() {
+    // (node platform, image platform, expected)
+    let cases = vec![
+        ("linux/amd64", "linux/amd64", true),
+        ("amd64", "windows/amd64", false),
+        ("linux/arm64", "linux/arm64/v8", true),
+        ("linux/arm/v7", "linux/arm/v7", true),
+        ("linux/arm/v7", "linux/arm/v6", true),
+        ("linux/arm/v6", "linux/arm/v7", false),
+        ("linux/arm/v7", "linux/arm64", false),
+        ("linux/amd64/v3", "linux/amd64", true),
+        ("linux/amd64", "linux/amd64/v3", false),
+        ("linux/arm64", "linux/amd64", false),
+    ];
+    for (node, image, expected) in cases {
+        let node: Platform = node.parse().unwrap();
+        let image: Platform = image.parse().unwrap();
+        assert_eq!(node.matches(&image), expected, "node {} running image {}", node, image);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::reference::{ImageReference, ReferenceError};
+
+const SHA256: &str = "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+// (input, domain, path, tag, digest, canonical)
+type NormalizeCase = (String, &'static str, &'static str, Option<&'static str>, Option<&'static str>, String);
+
+#[test]
+fn references_normalize_like_distribution() {
+    let cases: Vec<NormalizeCase> = vec![
+        ("nginx".into(), "docker.io", "library/nginx", None, None, "docker.io/library/nginx".into()),
+        ("nginx:1.23".into(), "docker.io", "library/nginx", Some("1.23"), None, "docker.io/library/nginx:1.23".into()),
+        ("library/nginx".into(), "docker.io", "library/nginx", None, None, "docker.io/library/nginx".into()),
+        ("docker.io/nginx".into(), "docker.io", "library/nginx", None, None, "docker.io/library/nginx".into()),
+        ("index.docker.io/nginx".into(), "docker.io", "library/nginx", None, None, "docker.io/library/nginx".into()),
+        ("Index.Docker.IO/bitnami/redis:7".into(), "docker.io", "bitnami/redis", Some("7"), None, "docker.io/bitnami/redis:7".into()),
+        ("bitnami/redis".into(), "docker.io", "bitnami/redis", None, None, "docker.io/bitnami/redis".into()),
+        ("quay.io/metallb/controller:v0.13.9".into(), "quay.io", "metallb/controller", Some("v0.13.9"), None, "quay.io/metallb/controller:v0.13.9".into()),
+        ("Quay.IO/metallb/controller".into(), "quay.io", "metallb/controller", None, None, "quay.io/metallb/controller".into()),
+        ("ghcr.io/a/b/c/d:tag".into(), "ghcr.io", "a/b/c/d", Some("tag"), None, "ghcr.io/a/b/c/d:tag".into()),
+        // a single component is fine anywhere but docker hub.
+        ("quay.io/busybox".into(), "quay.io", "busybox", None, None, "quay.io/busybox".into()),
+        ("localhost/nginx".into(), "localhost", "nginx", None, None, "localhost/nginx".into()),
+        ("localhost:5001/nginx:1.23".into(), "localhost:5001", "nginx", Some("1.23"), None, "localhost:5001/nginx:1.23".into()),
+        ("LOCALHOST:5001/nginx".into(), "localhost:5001", "nginx", None, None, "localhost:5001/nginx".into()),
+        ("registry.local:5000/team/app:1.0".into(), "registry.local:5000", "team/app", Some("1.0"), None, "registry.local:5000/team/app:1.0".into()),
+        ("127.0.0.1:5000/app".into(), "127.0.0.1:5000", "app", None, None, "127.0.0.1:5000/app".into()),
+        ("[::1]:5000/app:v1".into(), "[::1]:5000", "app", Some("v1"), None, "[::1]:5000/app:v1".into()),
+        ("[2001:db8::1]/app".into(), "[2001:db8::1]", "app", None, None, "[2001:db8::1]/app".into()),
+        ("[FE80::1]:443/app".into(), "[fe80::1]:443", "app", None, None, "[fe80::1]:443/app".into()),
+        // a port-shaped tag on a name without a registry is still a tag.
+        ("localhost:5000".into(), "docker.io", "library/localhost", Some("5000"), None, "docker.io/library/localhost:5000".into()),
+        (format!("nginx@{SHA256}"), "docker.io", "library/nginx", None, Some(SHA256), format!("docker.io/library/nginx@{SHA256}")),
+        (format!("nginx:1.23@{SHA256}"), "docker.io", "library/nginx", Some("1.23"), Some(SHA256), format!("docker.io/library/nginx:1.23@{SHA256}")),
+        (format!("[::1]:5000/app@{SHA256}"), "[::1]:5000", "app", None, Some(SHA256), format!("[::1]:5000/app@{SHA256}")),
+        ("a.b-c.e---f/g_h:TAG_1.x-y".into(), "a.b-c.e---f", "g_h", Some("TAG_1.x-y"), None, "a.b-c.e---f/g_h:TAG_1.x-y".into()),
+        ("foo/foo_bar.com:8080".into(), "docker.io", "foo/foo_bar.com", Some("8080"), None, "docker.io/foo/foo_bar.com:8080".into()),
+        ("sub-dom1.foo.com/bar/baz/quux".into(), "sub-dom1.foo.com", "bar/baz/quux", None, None, "sub-dom1.foo.com/bar/baz/quux".into()),
+        ("xn--n3h.com/myimage:xn--n3h.com".into(), "xn--n3h.com", "myimage", Some("xn--n3h.com"), None, "xn--n3h.com/myimage:xn--n3h.com".into()),
+    ];
+    for (input, domain, path, tag, digest, canonical) in cases {
+        let reference = ImageReference::parse(&input).unwrap_or_else(|e| panic!("{input}: {e}"));
+        assert_eq!(reference.domain, domain, "{input}");
+        assert_eq!(reference.path, path, "{input}");
+        assert_eq!(reference.tag.as_deref(), tag, "{input}");
+        assert_eq!(reference.digest.as_deref(), digest, "{input}");
+        assert_eq!(reference.to_string(), canonical, "{input}");
+        // the canonical form means the same thing.
+        assert_eq!(canonical.parse::<ImageReference>().unwrap(), reference, "{input}");
+    }
+}
+
+#[test]
+fn invalid_references() {
+    let long_name = format!("quay.io/{}", "a".repeat(250));
+    let long_tag = format!("nginx:{}", "a".repeat(129));
+    // (input, error)
+    let cases: Vec<(&str, ReferenceError)> = vec![
+        ("", ReferenceError::Empty),
+        (":latest", ReferenceError::InvalidFormat),
+        ("/nginx", ReferenceError::InvalidFormat),
+        ("nginx/", ReferenceError::InvalidFormat),
+        ("quay.io/", ReferenceError::InvalidFormat),
+        ("nginx//latest", ReferenceError::InvalidFormat),
+        ("-nginx", ReferenceError::InvalidFormat),
+        ("nginx-", ReferenceError::InvalidFormat),
+        ("ngi...nx", ReferenceError::InvalidFormat),
+        ("ngi___nx", ReferenceError::InvalidFormat),
+        ("a b", ReferenceError::InvalidFormat),
+        ("Nginx", ReferenceError::NameContainsUppercase),
+        ("quay.io/MetalLB/controller", ReferenceError::NameContainsUppercase),
+        (&long_name, ReferenceError::NameTooLong),
+        ("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad", ReferenceError::Identifier),
+        ("nginx:", ReferenceError::InvalidTag("".into())),
+        ("nginx:-dash", ReferenceError::InvalidTag("-dash".into())),
+        ("nginx:.dot", ReferenceError::InvalidTag(".dot".into())),
+        ("nginx:a+b", ReferenceError::InvalidTag("a+b".into())),
+        (&long_tag, ReferenceError::InvalidTag("a".repeat(129))),
+        ("nginx@sha256:abc", ReferenceError::InvalidDigest("sha256:abc".into())),
+        ("nginx@sha256", ReferenceError::InvalidDigest("sha256".into())),
+        ("nginx@", ReferenceError::InvalidDigest("".into())),
+        (
+            "nginx@sha256:BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD",
+            ReferenceError::InvalidDigest("sha256:BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD".into()),
+        ),
+        ("nginx@1sha:ba7816bf8f01cfea414140de5dae2223", ReferenceError::InvalidDigest("1sha:ba7816bf8f01cfea414140de5dae2223".into())),
+        ("nginx@sha256:ba78@sha256:ba78", ReferenceError::InvalidDigest("sha256:ba78@sha256:ba78".into())),
+        ("-quay.io/nginx", ReferenceError::InvalidDomain("-quay.io".into())),
+        ("quay..io/nginx", ReferenceError::InvalidDomain("quay..io".into())),
+        ("quay.io:/nginx", ReferenceError::InvalidDomain("quay.io:".into())),
+        ("quay.io:80a/nginx", ReferenceError::InvalidDomain("quay.io:80a".into())),
+        ("[::1/nginx", ReferenceError::InvalidDomain("[::1".into())),
+        ("[not-ipv6]:5000/nginx", ReferenceError::InvalidDomain("[not-ipv6]:5000".into())),
+        ("[::1]5000/nginx", ReferenceError::InvalidDomain("[::1]5000".into())),
+        ("::1/nginx", ReferenceError::InvalidDomain("::1".into())),
+    ];
+    for (input, error) in cases {
+        assert_eq!(ImageReference::parse(input), Err(error), "{input}");
+    }
+}
+
+#[test]
+fn references_pick_what_to_ask_for() {
+    let reference = ImageReference::parse("nginx").unwrap();
+    assert_eq!(reference.reference(), "latest");
+    assert_eq!(reference.name(), "docker.io/library/nginx");
+    assert_eq!(reference.with_default_tag().to_string(), "docker.io/library/nginx:latest");
+    let reference = ImageReference::parse(&format!("nginx:1.23@{SHA256}")).unwrap();
+    // a digest wins over a tag.
+    assert_eq!(reference.reference(), SHA256);
+    assert_eq!(reference.clone().with_default_tag(), reference);
+    let reference = ImageReference::parse(&format!("nginx@{SHA256}")).unwrap().with_default_tag();
+    assert_eq!(reference.tag, None);
+    let sha512 = format!("sha512:{}", "0123456789abcdef".repeat(8));
+    assert_eq!(ImageReference::parse(&format!("nginx@{sha512}")).unwrap().reference(), sha512);
+}
+
+#[test]
+fn upper_case_first_components_are_hosts() {
+    // repositories can't have upper-case letters, so this must be a registry, and hosts are case-insensitive.
+    let reference = ImageReference::parse("Registry/nginx").unwrap();
+    assert_eq!(reference.domain, "registry");
+    assert_eq!(reference.path, "nginx");
+    // up to the limit of 255 characters for the whole name.
+    let longest = format!("quay.io/{}", "a".repeat(247));
+    assert_eq!(ImageReference::parse(&longest).unwrap().name(), longest);
+}
@@ -0,0 +1,181 @@
+use crate::manifest::normalize_image;
+use crate::hosts::parse_hosts_toml;
+use crate::registry::{is_loopback, registry_host, AuthMode, Endpoint, Registries, RegistrySettings};
+use config::{Config, File, FileFormat};
+use std::collections::HashMap;
+use std::path::Path;
+
+fn registries(toml: &str) -> Registries {
+    let config = Config::builder()
+        .add_source(File::from_str(toml, FileFormat::Toml))
+        .build()
+        .unwrap();
+    Registries::new(config.get::<HashMap<String, RegistrySettings>>("registries").unwrap())
+}
+
+#[test]
+fn loopback_registries() {
+    for registry in ["localhost", "localhost:5001", "LOCALHOST:5001", "127.0.0.1:5000", "127.1.2.3", "[::1]:5000", "::1"] {
+        assert!(is_loopback(registry), "{registry}");
+    }
+    for registry in ["registry.local:5000", "quay.io", "10.0.0.1:5000", "[fe80::1]:5000", "localhost.example.com"] {
+        assert!(!is_loopback(registry), "{registry}");
+    }
+}
+
+#[test]
+fn insecure_registries_use_plain_http() {
+    let registries = registries(
+        r#"
+        [registries."registry.local:5000"]
+        insecure = true
+        [registries."localhost:5002"]
+        insecure = false
+        "#,
+    );
+    assert_eq!(registries.base_url("registry.local:5000"), "http://registry.local:5000");
+    assert_eq!(registries.base_url("Registry.Local:5000"), "http://Registry.Local:5000");
+    assert_eq!(registries.base_url("localhost:5001"), "http://localhost:5001");
+    // loopback registries can still opt back into https.
+    assert_eq!(registries.base_url("localhost:5002"), "https://localhost:5002");
+    assert_eq!(registries.base_url("quay.io"), "https://quay.io");
+}
+
+#[test]
+fn registries_resolve_names_and_aliases() {
+    let registries = registries(
+        r#"
+        [registries."harbor.example.com"]
+        endpoint = "https://harbor-internal.example.com:8443/"
+        aliases = ["harbor", "Harbor.Example.Org"]
+        timeout_seconds = 10
+        retries = 2
+        concurrency = 4
+        auth = "basic"
+        credential_file = "/opt/tolerable/creds/harbor.toml"
+        [registries."docker.io"]
+        retries = 1
+        [registries."registry.local:5000"]
+        endpoint = "http://registry.local:5000"
+        [registries."ghcr.io"]
+        enabled = false
+        "#,
+    );
+    let (name, harbor) = registries.resolve("harbor.example.org");
+    assert_eq!(name, "harbor.example.com");
+    assert_eq!(harbor.endpoint_host().as_deref(), Some("harbor-internal.example.com:8443"));
+    assert_eq!(harbor.timeout_seconds, Some(10));
+    assert_eq!(harbor.retries, 2);
+    assert_eq!(harbor.concurrency, Some(4));
+    assert_eq!(harbor.auth, AuthMode::Basic);
+    assert_eq!(harbor.credential_file.as_deref(), Some("/opt/tolerable/creds/harbor.toml"));
+    assert!(harbor.enabled);
+    assert_eq!(registries.resolve("harbor").0, "harbor.example.com");
+    assert_eq!(registries.for_endpoint("harbor-internal.example.com:8443"), harbor);
+    assert_eq!(
+        registries.base_url("harbor-internal.example.com:8443"),
+        "https://harbor-internal.example.com:8443"
+    );
+
+    // docker hub's own alias still works, and picks up its settings.
+    let (name, docker) = registries.resolve("docker.io");
+    assert_eq!(name, "registry-1.docker.io");
+    assert_eq!(docker.retries, 1);
+    assert_eq!(registries.resolve("index.docker.io").0, "registry-1.docker.io");
+
+    assert_eq!(registries.base_url("registry.local:5000"), "http://registry.local:5000");
+    assert!(!registries.resolve("ghcr.io").1.enabled);
+    let (name, quay) = registries.resolve("Quay.io");
+    assert_eq!(name, "quay.io");
+    assert_eq!(quay, RegistrySettings::default());
+    assert_eq!(quay.auth, AuthMode::Auto);
+}
+
+#[test]
+fn library_prefix_is_for_docker_hub() {
+    let target = normalize_image("nginx").unwrap();
+    assert_eq!(target.registry, "docker.io");
+    assert_eq!(target.registryport, "registry-1.docker.io");
+    assert_eq!(target.image_name, "library/nginx");
+    let target = normalize_image("localhost:5001/nginx:1.23").unwrap();
+    assert_eq!(target.registryport, "localhost:5001");
+    assert_eq!(target.image_name, "nginx");
+    assert_eq!(target.reference, "1.23");
+}
+
+#[test]
+fn mirrors_come_before_the_registry() {
+    let hosts = parse_hosts_toml(
+        r#"
+        server = "https://registry-1.docker.io"
+        [host."https://zot.example.com:5000"]
+        [host."https://harbor.example.com/v2/dockerhub-proxy"]
+          capabilities = ["pull"]
+          override_path = true
+        [host."https://push-only.example.com"]
+          capabilities = ["push"]
+        "#,
+        Path::new("/etc/containerd/certs.d/docker.io"),
+    )
+    .unwrap();
+    let registries = registries(
+        r#"
+        [registries."docker.io"]
+        mirrors = ["https://harbor.example.com/v2/proxy-cache/", "localhost:5001"]
+        [registries."quay.io"]
+        endpoint = "quay-internal.example.com"
+        "#,
+    )
+    .with_hosts_files(HashMap::from([("registry-1.docker.io".to_string(), hosts)]));
+
+    let endpoint = |host: &str, api_root: &str, resolve: bool, mirror: bool| Endpoint {
+        host: host.to_string(),
+        api_root: api_root.to_string(),
+        resolve,
+        mirror,
+    };
+    let (name, settings) = registries.resolve("docker.io");
+    assert_eq!(
+        registries.endpoints(&name, &settings),
+        vec![
+            endpoint("harbor.example.com", "https://harbor.example.com/v2/proxy-cache", true, true),
+            endpoint("localhost:5001", "http://localhost:5001/v2", true, true),
+            endpoint("zot.example.com:5000", "https://zot.example.com:5000/v2", true, true),
+            endpoint("harbor.example.com", "https://harbor.example.com/v2/dockerhub-proxy", false, true),
+            endpoint("registry-1.docker.io", "https://registry-1.docker.io/v2", true, false),
+        ]
+    );
+    let (name, settings) = registries.resolve("quay.io");
+    assert_eq!(
+        registries.endpoints(&name, &settings),
+        vec![endpoint("quay-internal.example.com", "https://quay-internal.example.com/v2", true, false)]
+    );
+}
+
+#[test]
+fn endpoints_are_pinged_at_the_root_of_their_host_and_prefix_repositories() {
+    let harbor = Endpoint {
+        host: "harbor.example.com".to_string(),
+        api_root: "https://harbor.example.com/v2/dockerhub-proxy".to_string(),
+        resolve: false,
+        mirror: true,
+    };
+    assert_eq!(harbor.ping_url(), "https://harbor.example.com/v2/");
+    assert_eq!(harbor.repository("library/nginx"), "dockerhub-proxy/library/nginx");
+    let local = Endpoint {
+        host: "localhost:5001".to_string(),
+        api_root: "http://localhost:5001/v2".to_string(),
+        resolve: true,
+        mirror: true,
+    };
+    assert_eq!(local.ping_url(), "http://localhost:5001/v2/");
+    assert_eq!(local.repository("library/nginx"), "library/nginx");
+}
+
+#[test]
+fn registry_hosts() {
+    assert_eq!(registry_host("docker.io"), "registry-1.docker.io");
+    assert_eq!(registry_host("Index.Docker.IO"), "registry-1.docker.io");
+    assert_eq!(registry_host("Quay.IO"), "quay.io");
+    assert_eq!(registry_host("localhost:5001"), "localhost:5001");
+}
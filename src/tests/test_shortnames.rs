@@ -0,0 +1,76 @@
+use crate::manifest::normalize_image;
+use crate::shortnames::{is_qualified, RegistriesConf};
+use crate::tests::temp_dir::TempDir;
+use std::fs;
+
+#[test]
+fn qualified_names() {
+    for image in ["quay.io/metallb/controller", "localhost/nginx", "LocalHost/nginx", "localhost:5001/nginx:1.23", "registry.local:5000/a/b"] {
+        assert!(is_qualified(image), "{image}");
+    }
+    for image in ["nginx", "nginx:1.23", "library/nginx", "bitnami/redis@sha256:abc"] {
+        assert!(!is_qualified(image), "{image}");
+    }
+}
+
+#[test]
+fn short_names_follow_registries_conf() {
+    let conf = RegistriesConf::parse(
+        r#"
+        unqualified-search-registries = ["registry.fedoraproject.org", "quay.io", "docker.io"]
+        short-name-mode = "enforcing"
+
+        [aliases]
+        "fedora" = "registry.fedoraproject.org/fedora"
+        "bitnami/redis" = "quay.io/bitnami/redis"
+
+        [[registry]]
+        location = "quay.io"
+        "#,
+    )
+    .unwrap();
+    // (image, candidates)
+    let cases = vec![
+        ("fedora:38", vec!["registry.fedoraproject.org/fedora:38"]),
+        ("bitnami/redis@sha256:abc", vec!["quay.io/bitnami/redis@sha256:abc"]),
+        ("fedora-minimal", vec!["registry.fedoraproject.org/fedora-minimal", "quay.io/fedora-minimal", "docker.io/fedora-minimal"]),
+        ("nginx:1.23", vec!["registry.fedoraproject.org/nginx:1.23", "quay.io/nginx:1.23", "docker.io/nginx:1.23"]),
+        ("ghcr.io/petergrace/tolerable", vec!["ghcr.io/petergrace/tolerable"]),
+    ];
+    for (image, candidates) in cases {
+        assert_eq!(conf.candidates(image), candidates, "{image}");
+    }
+    // only docker hub gets library/ added.
+    assert_eq!(normalize_image("quay.io/nginx").unwrap().image_name, "nginx");
+    assert_eq!(normalize_image("docker.io/nginx").unwrap().image_name, "library/nginx");
+}
+
+#[test]
+fn short_names_default_to_docker_hub() {
+    let conf = RegistriesConf::default();
+    assert_eq!(conf.candidates("nginx"), vec!["docker.io/nginx"]);
+    let conf = RegistriesConf::parse("unqualified-search-registries = []").unwrap();
+    assert_eq!(conf.candidates("nginx:1.23"), vec!["docker.io/nginx:1.23"]);
+    assert!(RegistriesConf::parse("unqualified-search-registries = 1").is_err());
+}
+
+#[test]
+fn drop_ins_override_search_registries() {
+    let dir = TempDir::new("registries-conf");
+    fs::create_dir_all(dir.join("registries.conf.d")).unwrap();
+    fs::write(
+        dir.join("registries.conf"),
+        "unqualified-search-registries = [\"quay.io\"]\n[aliases]\n\"nginx\" = \"quay.io/nginx/nginx\"\n",
+    )
+    .unwrap();
+    fs::write(dir.join("registries.conf.d").join("10-search.conf"), "unqualified-search-registries = [\"ghcr.io\", \"docker.io\"]\n").unwrap();
+    fs::write(dir.join("registries.conf.d").join("20-aliases.conf"), "[aliases]\n\"redis\" = \"docker.io/library/redis\"\n").unwrap();
+    fs::write(dir.join("registries.conf.d").join("30-broken.conf"), "this is not toml").unwrap();
+    fs::write(dir.join("registries.conf.d").join("README"), "ignored").unwrap();
+
+    let conf = RegistriesConf::load(&dir.join("registries.conf"));
+    assert_eq!(conf.candidates("busybox"), vec!["ghcr.io/busybox", "docker.io/busybox"]);
+    assert_eq!(conf.candidates("nginx"), vec!["quay.io/nginx/nginx"]);
+    assert_eq!(conf.candidates("redis:7"), vec!["docker.io/library/redis:7"]);
+    assert_eq!(RegistriesConf::load(&dir.join("missing.conf")), RegistriesConf::default());
+}
@@ -0,0 +1,75 @@
+use crate::errors::LookupError;
+use crate::manifest::{normalize_image, ImagePlatforms};
+use crate::platform::Platform;
+use crate::singleflight::{wait_for_flight, Flight, InFlight};
+use actix_web::test;
+
+fn platforms() -> ImagePlatforms {
+    ImagePlatforms {
+        image: "nginx".to_string(),
+        digest: "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string(),
+        platforms: vec![Platform::new("linux", "arm64", None)],
+    }
+}
+
+#[test]
+async fn followers_share_the_leaders_result() {
+    let flights = InFlight::new();
+    let leader = match flights.join("registry-1.docker.io/library/nginx:latest") {
+        Flight::Leader(guard) => guard,
+        Flight::Follower(_) => panic!("first caller should lead"),
+    };
+    let follower = match flights.join("registry-1.docker.io/library/nginx:latest") {
+        Flight::Follower(receiver) => receiver,
+        Flight::Leader(_) => panic!("second caller should follow"),
+    };
+    leader.complete(Ok(platforms()));
+    assert_eq!(wait_for_flight(follower).await, Some(Ok(platforms())));
+    // the flight is over, so the next caller leads a new one.
+    assert!(matches!(flights.join("registry-1.docker.io/library/nginx:latest"), Flight::Leader(_)));
+}
+
+#[test]
+async fn followers_are_released_when_the_leader_gives_up() {
+    let flights = InFlight::new();
+    let leader = flights.join("registry-1.docker.io/library/nginx:latest");
+    let follower = match flights.join("registry-1.docker.io/library/nginx:latest") {
+        Flight::Follower(receiver) => receiver,
+        Flight::Leader(_) => panic!("second caller should follow"),
+    };
+    drop(leader);
+    assert_eq!(wait_for_flight(follower).await, None);
+}
+
+#[test]
+async fn followers_see_errors_too() {
+    let flights = InFlight::new();
+    let error = LookupError::Unreachable {
+        registry: "registry-1.docker.io".to_string(),
+        message: "connection refused".to_string(),
+    };
+    let leader = match flights.join("registry-1.docker.io/library/nginx:latest") {
+        Flight::Leader(guard) => guard,
+        Flight::Follower(_) => panic!("first caller should lead"),
+    };
+    let follower = match flights.join("registry-1.docker.io/library/nginx:latest") {
+        Flight::Follower(receiver) => receiver,
+        Flight::Leader(_) => panic!("second caller should follow"),
+    };
+    leader.complete(Err(error.clone()));
+    assert_eq!(wait_for_flight(follower).await, Some(Err(error)));
+}
+
+#[test]
+async fn spellings_of_one_image_share_a_key() {
+    let keys: Vec<String> = vec!["nginx", "nginx:latest", "docker.io/nginx", "docker.io/library/nginx:latest"]
+        .into_iter()
+        .map(|i| normalize_image(i).unwrap().key())
+        .collect();
+    assert!(keys.iter().all(|k| k == "registry-1.docker.io/library/nginx:latest"));
+    let pinned = "nginx:1.23@sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    assert_eq!(
+        normalize_image(pinned).unwrap().key(),
+        "registry-1.docker.io/library/nginx@sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+}
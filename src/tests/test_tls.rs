@@ -0,0 +1,61 @@
+use crate::tests::temp_dir::TempDir;
+use crate::tls::{certs_d_files, client_config, TlsFiles};
+use std::fs;
+
+#[test]
+fn certs_d_layout() {
+    let dir = TempDir::new("certs-d-layout");
+    let harbor = dir.join("harbor.example.com");
+    fs::create_dir_all(&harbor).unwrap();
+    fs::write(harbor.join("ca.crt"), "").unwrap();
+    fs::write(harbor.join("client.cert"), "").unwrap();
+    fs::write(harbor.join("client.key"), "").unwrap();
+    let local = dir.join("registry.local:5000");
+    fs::create_dir_all(&local).unwrap();
+    fs::write(local.join("internal-ca.crt"), "").unwrap();
+    // a client certificate without its key is no use.
+    fs::write(local.join("client.cert"), "").unwrap();
+    let docker = dir.join("docker.io");
+    fs::create_dir_all(&docker).unwrap();
+    fs::write(docker.join("ca.crt"), "").unwrap();
+    // nothing we can use in here.
+    fs::create_dir_all(dir.join("quay.io")).unwrap();
+    fs::write(dir.join("quay.io").join("hosts.toml"), "").unwrap();
+
+    let registries = certs_d_files(&dir);
+    assert_eq!(registries.len(), 3);
+    assert_eq!(
+        registries["harbor.example.com"],
+        TlsFiles {
+            ca_files: vec![harbor.join("ca.crt")],
+            client_cert: Some((harbor.join("client.cert"), harbor.join("client.key"))),
+        }
+    );
+    assert_eq!(
+        registries["registry.local:5000"],
+        TlsFiles {
+            ca_files: vec![local.join("internal-ca.crt")],
+            client_cert: None,
+        }
+    );
+    assert!(registries.contains_key("registry-1.docker.io"));
+    assert!(certs_d_files(&dir.join("does-not-exist")).is_empty());
+}
+
+#[test]
+fn client_config_needs_usable_files() {
+    assert!(client_config(&TlsFiles::default()).is_ok());
+
+    let dir = TempDir::new("client-config");
+    fs::write(dir.join("empty.crt"), "").unwrap();
+    let missing = TlsFiles {
+        ca_files: vec![dir.join("missing.crt")],
+        client_cert: None,
+    };
+    assert!(client_config(&missing).is_err());
+    let empty = TlsFiles {
+        ca_files: vec![dir.join("empty.crt")],
+        client_cert: None,
+    };
+    assert!(client_config(&empty).is_err());
+}
@@ -0,0 +1,118 @@
+use crate::registry::registry_host;
+use anyhow::{anyhow, bail, Context};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore};
+use std::collections::HashMap;
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// TlsFiles are the extra certificates used when talking to one registry.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TlsFiles {
+    /// ca_files are pem bundles of CAs trusted on top of the usual public roots
+    pub ca_files: Vec<PathBuf>,
+    /// client_cert is the certificate and key presented to registries that require mutual tls
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+impl TlsFiles {
+    pub fn is_empty(&self) -> bool {
+        self.ca_files.is_empty() && self.client_cert.is_none()
+    }
+}
+
+/// certs_d_files reads a containerd/docker `certs.d` directory: one directory per registry host, holding CA
+/// bundles (`*.crt`) and client certificates (`*.cert`, with the key next to it as `*.key`).  A missing directory
+/// simply has no registries in it.
+pub fn certs_d_files(dir: &Path) -> HashMap<String, TlsFiles> {
+    let mut registries: HashMap<String, TlsFiles> = HashMap::new();
+    let hosts = match fs::read_dir(dir) {
+        Ok(h) => h,
+        Err(_) => return registries,
+    };
+    for host in hosts.flatten() {
+        if !host.path().is_dir() {
+            continue;
+        }
+        let name = registry_host(&host.file_name().to_string_lossy());
+        let mut files = TlsFiles::default();
+        let mut entries: Vec<PathBuf> = match fs::read_dir(host.path()) {
+            Ok(e) => e.flatten().map(|e| e.path()).collect(),
+            Err(e) => {
+                warn!("unable to read {}: {e}", host.path().display());
+                continue;
+            }
+        };
+        entries.sort();
+        for path in entries {
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("crt") => files.ca_files.push(path),
+                Some("cert") => {
+                    let key = path.with_extension("key");
+                    if key.exists() {
+                        files.client_cert = Some((path, key));
+                    } else {
+                        warn!("client certificate {} has no matching .key, ignoring it", path.display());
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !files.is_empty() {
+            registries.insert(name, files);
+        }
+    }
+    registries
+}
+
+/// client_config builds the tls config for a registry: the public roots plus its own CAs, and its client
+/// certificate if it has one.
+pub fn client_config(files: &TlsFiles) -> anyhow::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+    }));
+    for ca_file in &files.ca_files {
+        let certs = load_certs(ca_file)?;
+        if certs.is_empty() {
+            bail!("no certificates found in {}", ca_file.display());
+        }
+        for cert in certs {
+            roots
+                .add(&cert)
+                .with_context(|| format!("invalid CA certificate in {}", ca_file.display()))?;
+        }
+    }
+    let builder = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots);
+    let mut config = match &files.client_cert {
+        Some((cert_file, key_file)) => builder
+            .with_single_cert(load_certs(cert_file)?, load_private_key(key_file)?)
+            .with_context(|| format!("invalid client certificate {}", cert_file.display()))?,
+        None => builder.with_no_client_auth(),
+    };
+    // the same protocols awc offers with its own default config.
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn load_certs(filename: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let certfile = fs::File::open(filename).with_context(|| format!("cannot open {}", filename.display()))?;
+    let mut reader = BufReader::new(certfile);
+    let certs = rustls_pemfile::certs(&mut reader).with_context(|| format!("cannot parse {}", filename.display()))?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(filename: &Path) -> anyhow::Result<PrivateKey> {
+    let keyfile = fs::File::open(filename).with_context(|| format!("cannot open {}", filename.display()))?;
+    let mut reader = BufReader::new(keyfile);
+    loop {
+        match rustls_pemfile::read_one(&mut reader).with_context(|| format!("cannot parse {}", filename.display()))? {
+            Some(rustls_pemfile::Item::RSAKey(key)) => return Ok(PrivateKey(key)),
+            Some(rustls_pemfile::Item::PKCS8Key(key)) => return Ok(PrivateKey(key)),
+            Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            None => break,
+            _ => {}
+        }
+    }
+    Err(anyhow!("no keys found in {} (encrypted keys not supported)", filename.display()))
+}